@@ -0,0 +1,39 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use kvs::{KvStore, KvStoreOptions};
+use tempfile::TempDir;
+
+const PAIRS: usize = 1000;
+
+// Bulk writes through the default 8 KiB log buffer against a 256 KiB one
+fn bulk_write(c: &mut Criterion) {
+    let pairs: Vec<(String, String)> = (0..PAIRS)
+        .map(|id| (format!("key{}", id), "v".repeat(256)))
+        .collect();
+    let mut group = c.benchmark_group("bulk_write");
+    group.throughput(Throughput::Elements(PAIRS as u64));
+    for capacity in [8 * 1024, 256 * 1024] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(capacity),
+            &capacity,
+            |b, &capacity| {
+                b.iter_batched(
+                    || {
+                        let temp_dir = TempDir::new().unwrap();
+                        let options = KvStoreOptions::default().buffer_capacity(capacity);
+                        let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+                        (temp_dir, store)
+                    },
+                    |(temp_dir, store)| {
+                        store.set_many(pairs.clone()).unwrap();
+                        (temp_dir, store)
+                    },
+                    BatchSize::PerIteration,
+                )
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bulk_write);
+criterion_main!(benches);
@@ -1,11 +1,139 @@
-use clap::StructOpt;
-use kvs::{ClientArgs, KvsClient, Result};
+use clap::{AppSettings, Parser};
+use kvs::{
+    ClientArgs, Command, ErrorKind, KvStoreError, KvsClient, OutputFormat, Response, Result,
+};
+use serde_json::json;
+use std::{
+    io::{self, BufRead},
+    process::exit,
+};
 
-fn main() -> Result<()> {
+fn main() {
     let args = ClientArgs::parse();
+    if let Err(err) = run(args) {
+        eprintln!("{}", err);
+        exit(err.exit_code());
+    }
+}
+
+fn run(args: ClientArgs) -> Result<()> {
     let mut client = KvsClient::new(args.addr)?;
+    if let Some(token) = args.token {
+        client.authenticate(token)?;
+    }
+    if args.command == Command::Repl {
+        return repl(&mut client, args.output);
+    }
 
-    client.send(args.command)?;
+    if let Some(code) = send(&mut client, args.command, args.output)? {
+        exit(code);
+    }
+    Ok(())
+}
 
+/// A line read by `repl`, parsed like the command line minus the binary name.
+#[derive(Parser)]
+#[clap(setting(AppSettings::NoBinaryName))]
+struct ReplLine {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+/// Runs each line of stdin as a command until EOF. A line that doesn't parse, or that
+/// the server rejects, is reported on stderr without ending the session.
+fn repl(client: &mut KvsClient, output: OutputFormat) -> Result<()> {
+    for line in io::stdin().lock().lines() {
+        let line = line?;
+        let words = line.split_whitespace();
+        if words.clone().next().is_none() {
+            continue;
+        }
+        match ReplLine::try_parse_from(words) {
+            Ok(ReplLine {
+                command: Command::Repl,
+            }) => eprintln!("Already reading commands from stdin"),
+            Ok(ReplLine { command }) => {
+                send(client, command, output)?;
+            }
+            Err(err) => eprint!("{}", err),
+        }
+    }
     Ok(())
 }
+
+/// Sends `command` and prints the response, returning the code to exit with if the
+/// server answered with an error.
+fn send(client: &mut KvsClient, command: Command, output: OutputFormat) -> Result<Option<i32>> {
+    let is_get = matches!(command, Command::Get { .. });
+    let response = client.send(command)?;
+    if output == OutputFormat::Json {
+        return Ok(print_json(response, is_get));
+    }
+
+    match response {
+        // A missing key is an answer to `get`, but a failure for anything else
+        Response::Err(ErrorKind::KeyNotFound) if is_get => println!("{}", ErrorKind::KeyNotFound),
+        Response::Err(kind) => {
+            eprintln!("{}", kind);
+            return Ok(Some(KvStoreError::from(kind).exit_code()));
+        }
+        response => print_response(response),
+    }
+    Ok(None)
+}
+
+/// Prints `response` as one line of JSON. Errors go to stdout too, so scripts can parse
+/// them, but still fail the command.
+fn print_json(response: Response, is_get: bool) -> Option<i32> {
+    match response {
+        // A missing key is an answer to `get`, but a failure for anything else
+        Response::Err(ErrorKind::KeyNotFound) if is_get => {
+            println!("{}", json!({ "status": "ok", "value": null }))
+        }
+        Response::Err(ref kind) => {
+            println!("{}", response.to_json());
+            return Some(KvStoreError::from(kind.clone()).exit_code());
+        }
+        response => println!("{}", response.to_json()),
+    }
+    None
+}
+
+fn print_response(response: Response) {
+    match response {
+        Response::GetOk(value) => println!("{}", value),
+        Response::Err(kind) => eprintln!("{}", kind),
+        Response::SetOk(_) | Response::RmOk | Response::OpenOk | Response::AuthOk => {}
+        Response::WatchOk | Response::UnwatchOk => {}
+        Response::Changed { key, value } => match value {
+            Some(value) => println!("{} {}", key, value),
+            None => println!("{}", key),
+        },
+        Response::CasOk(swapped) => println!("{}", swapped),
+        Response::IncrOk(value) => println!("{}", value),
+        Response::RemovePrefixOk(removed) => println!("{}", removed),
+        Response::Values(pairs) => {
+            for (key, value) in pairs {
+                println!("{} {}", key, value);
+            }
+        }
+        Response::Keys(keys) => {
+            for key in keys {
+                println!("{}", key);
+            }
+        }
+        Response::BatchOk(responses) => responses.into_iter().for_each(print_response),
+        Response::Pong => println!("pong"),
+        Response::Flushed | Response::SelectOk => {}
+        Response::Version(version) => println!("{}", version),
+        Response::Stats {
+            live_keys,
+            dirt_bytes,
+            log_size_bytes,
+        } => {
+            println!("live_keys: {}", live_keys);
+            println!("dirt_bytes: {}", dirt_bytes);
+            println!("log_size_bytes: {}", log_size_bytes);
+        }
+    }
+}
@@ -1,10 +1,38 @@
 use clap::StructOpt;
 use kvs::{KvsServer, Result, ServerArgs};
+use log::LevelFilter;
+use std::process::exit;
 
-fn main() -> Result<()> {
-    env_logger::init();
+fn main() {
     let args = ServerArgs::parse();
-    let mut server = KvsServer::new(args.addr, args.engine, "")?;
+    env_logger::Builder::new()
+        .filter_level(args.log_level.unwrap_or(LevelFilter::Info))
+        .parse_env(env_logger::Env::default())
+        .init();
+    if let Err(err) = run(args) {
+        eprintln!("{}", err);
+        exit(err.exit_code());
+    }
+}
+
+fn run(args: ServerArgs) -> Result<()> {
+    let open = if args.read_only {
+        KvsServer::new_read_only
+    } else {
+        KvsServer::new
+    };
+    let mut server = open(
+        args.addr,
+        args.engine,
+        args.data_dir.unwrap_or_default(),
+        args.config,
+    )?;
+    if let Some(token) = args.auth_token {
+        server = server.with_auth_token(token);
+    }
+    if let Some(nodelay) = args.tcp_nodelay {
+        server = server.tcp_nodelay(nodelay);
+    }
     server.run()?;
 
     Ok(())
@@ -0,0 +1,51 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+};
+
+/// Bits per expected key, giving about 1% false positives with `HASHES` hashes.
+const BITS_PER_KEY: usize = 10;
+const HASHES: u64 = 7;
+
+/// A set of keys that can tell for certain that a key was never inserted, but may
+/// mistake an absent key for a present one.
+///
+/// Keys can't be taken out, so a removed key still looks present until the filter is
+/// rebuilt without it.
+#[derive(Debug)]
+pub(crate) struct BloomFilter {
+    bits: Vec<u64>,
+}
+
+impl BloomFilter {
+    /// Returns an empty filter sized for `expected_keys`. More keys than that raise the
+    /// false positive rate, never the false negative one, which stays 0.
+    pub(crate) fn new(expected_keys: usize) -> Self {
+        let words = (expected_keys.max(1) * BITS_PER_KEY).div_ceil(64);
+        Self {
+            bits: vec![0; words],
+        }
+    }
+
+    pub(crate) fn insert(&mut self, key: &str) {
+        for bit in self.bit_indexes(key) {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    /// Returns `false` only if `key` was never inserted.
+    pub(crate) fn may_contain(&self, key: &str) -> bool {
+        self.bit_indexes(key)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    /// The bits `key` sets, derived from one hash by double hashing.
+    fn bit_indexes(&self, key: &str) -> impl Iterator<Item = usize> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let hash = hasher.finish();
+        let step = hash.rotate_left(32) | 1;
+        let len = self.bits.len() as u64 * 64;
+        (0..HASHES).map(move |i| (hash.wrapping_add(i.wrapping_mul(step)) % len) as usize)
+    }
+}
@@ -1,15 +1,21 @@
-use crate::{kvs_error::Result, response::Response};
-use bincode::{de::read::IoReader, deserialize_from, serialize_into, Deserializer};
-use clap::{AppSettings, Parser, Subcommand};
+use crate::{
+    frame::{read_frame, write_frame},
+    kvs_error::Result,
+    response::Response,
+    transport::{parse_addr, Stream},
+    KvStoreError,
+};
+use clap::{AppSettings, ArgEnum, Parser, Subcommand};
+use log::debug;
 use serde::{Deserialize, Serialize};
 use std::{
-    io::{BufReader, BufWriter, Read, Write},
-    net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream},
+    io::{self, BufReader, BufWriter},
     path::PathBuf,
-    process::exit,
+    thread,
+    time::{Duration, Instant},
 };
 
-#[derive(Hash, Debug, Eq, PartialEq, Subcommand, Serialize, Deserialize)]
+#[derive(Hash, Debug, Clone, Eq, PartialEq, Subcommand, Serialize, Deserialize)]
 pub enum Command {
     #[clap(setting(AppSettings::ArgRequiredElseHelp))]
     Set {
@@ -27,10 +33,175 @@ pub enum Command {
     Open {
         path: PathBuf,
     },
+    /// Sets a key to a new value only if it currently holds the expected one, or is
+    /// absent when no expected value is given.
+    #[clap(setting(AppSettings::ArgRequiredElseHelp))]
+    Cas {
+        key: String,
+        #[clap(long)]
+        expected: Option<String>,
+        new: String,
+    },
+    /// Adds a delta to the integer stored at a key, which counts as 0 when absent.
+    #[clap(setting(AppSettings::ArgRequiredElseHelp))]
+    Incr {
+        key: String,
+        #[clap(allow_hyphen_values = true)]
+        delta: i64,
+    },
+    /// Lists the key/value pairs whose keys start with a prefix.
+    PrefixScan {
+        prefix: String,
+    },
+    /// Removes every key starting with a prefix.
+    RemovePrefix {
+        prefix: String,
+    },
+    /// Lists the key/value pairs whose keys are at least `start` and below `end`. A
+    /// missing bound leaves that end of the range open.
+    Scan {
+        #[clap(long)]
+        start: Option<String>,
+        #[clap(long)]
+        end: Option<String>,
+    },
+    /// Checks that the server is up, without touching its store.
+    Ping,
+    /// Asks the server which version of kvs it runs.
+    Version,
+    /// Asks the server for its engine's stats.
+    Stats,
+    /// Waits until every write the server has acknowledged is synced to disk.
+    Flush,
+    /// Switches the connection to a numbered database, whose keys are kept apart from
+    /// every other database's. Connections start out in database 0.
+    #[clap(setting(AppSettings::ArgRequiredElseHelp))]
+    Select {
+        db: u16,
+    },
+    /// Reads commands from stdin, one per line, and runs each over the same connection
+    /// until EOF. Handled by the client; the server never sees it.
+    Repl,
+    /// Streams `Response::Changed` whenever a key is set or removed, until `Unwatch`.
+    #[clap(skip)]
+    Watch {
+        #[clap(skip)]
+        key: String,
+    },
+    /// Stops every watch of the connection.
+    #[clap(skip)]
+    Unwatch,
+    /// Authenticates the connection to a server started with an auth token.
+    #[clap(skip)]
+    Auth {
+        #[clap(skip)]
+        token: String,
+    },
+    /// Commands run in order in a single round trip. Batches cannot be nested.
+    #[clap(skip)]
+    Batch {
+        #[clap(skip)]
+        commands: Vec<Command>,
+    },
+    #[clap(skip)]
+    SetBytes {
+        key: String,
+        value: Vec<u8>,
+    },
+    #[clap(skip)]
+    SetCompressed {
+        key: String,
+        value: Vec<u8>,
+    },
+    #[clap(skip)]
+    SetWithTtl {
+        key: String,
+        value: String,
+        expires_at_unix_ms: u64,
+    },
 }
 
-#[derive(Debug)]
+impl Command {
+    /// A short name for the command, used in logs.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Command::Set { .. } => "set",
+            Command::Get { .. } => "get",
+            Command::Rm { .. } => "rm",
+            Command::Open { .. } => "open",
+            Command::Cas { .. } => "cas",
+            Command::Incr { .. } => "incr",
+            Command::PrefixScan { .. } => "prefix-scan",
+            Command::RemovePrefix { .. } => "remove-prefix",
+            Command::Scan { .. } => "scan",
+            Command::Ping => "ping",
+            Command::Version => "version",
+            Command::Stats => "stats",
+            Command::Flush => "flush",
+            Command::Select { .. } => "select",
+            Command::Repl => "repl",
+            Command::Watch { .. } => "watch",
+            Command::Unwatch => "unwatch",
+            Command::Auth { .. } => "auth",
+            Command::Batch { .. } => "batch",
+            Command::SetBytes { .. } => "set-bytes",
+            Command::SetCompressed { .. } => "set-compressed",
+            Command::SetWithTtl { .. } => "set-with-ttl",
+        }
+    }
+
+    /// The key the command acts on, if it acts on a single key.
+    pub(crate) fn key(&self) -> Option<&str> {
+        match self {
+            Command::Set { key, .. }
+            | Command::Get { key }
+            | Command::Rm { key }
+            | Command::Cas { key, .. }
+            | Command::Incr { key, .. }
+            | Command::Watch { key }
+            | Command::SetBytes { key, .. }
+            | Command::SetCompressed { key, .. }
+            | Command::SetWithTtl { key, .. } => Some(key),
+            _ => None,
+        }
+    }
+
+    /// Whether the command changes the store, or swaps it for another with `Open`. A
+    /// `Batch` counts by its own commands, not as a whole.
+    pub(crate) fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Command::Set { .. }
+                | Command::Rm { .. }
+                | Command::Open { .. }
+                | Command::Cas { .. }
+                | Command::Incr { .. }
+                | Command::RemovePrefix { .. }
+                | Command::SetBytes { .. }
+                | Command::SetCompressed { .. }
+                | Command::SetWithTtl { .. }
+        )
+    }
+
+    /// Whether running the command twice leaves the store and the response as running it
+    /// once would, so it is safe to send again when it is unknown whether it ran.
+    pub(crate) fn is_idempotent(&self) -> bool {
+        match self {
+            Command::Rm { .. }
+            | Command::Cas { .. }
+            | Command::Incr { .. }
+            | Command::RemovePrefix { .. } => false,
+            Command::Batch { commands } => commands.iter().all(Command::is_idempotent),
+            _ => true,
+        }
+    }
+}
+
+/// Where a command sits in the log: its generation, and its offset and length in bytes
+/// within that generation's file.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub struct CommandPosition {
+    pub gen: u64,
     pub start: u64,
     pub length: u64,
 }
@@ -40,45 +211,310 @@ pub struct CommandPosition {
 pub struct ClientArgs {
     #[clap(subcommand)]
     pub command: Command,
-    #[clap(short, long)]
+    #[clap(short, long, global = true)]
     pub addr: Option<String>,
+    /// The token the server was started with, if any.
+    #[clap(long, global = true)]
+    pub token: Option<String>,
+    #[clap(short, long, global = true, arg_enum, default_value = "human")]
+    pub output: OutputFormat,
+}
+
+/// How `kvs-client` prints responses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+pub enum OutputFormat {
+    /// Just the values asked for, one per line.
+    Human,
+    /// One JSON object per response, as rendered by `Response::to_json`.
+    Json,
 }
 
 #[derive(Debug)]
 pub struct KvsClient {
-    addr: SocketAddr,
-    writer: BufWriter<TcpStream>,
-    reader: BufReader<TcpStream>,
+    writer: BufWriter<Stream>,
+    reader: BufReader<Stream>,
 }
 
 impl KvsClient {
+    /// Connects to `addr`, a TCP address or a Unix domain socket written as
+    /// `unix:/path/to.sock`, or to `127.0.0.1:4000` when it is absent.
     pub fn new(addr: Option<String>) -> Result<Self> {
-        let sock_addr;
-
-        match addr {
-            Some(addr) => match addr.parse::<SocketAddr>() {
-                Ok(sock) => sock_addr = sock,
-                Err(_) => {
-                    eprintln!("invalid address, dumbass");
-                    exit(1);
-                }
-            },
-            None => sock_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000),
-        }
+        Self::with_timeout(addr, None)
+    }
 
-        let socket = TcpStream::connect(sock_addr.clone())?;
+    /// Connects like `new`, but gives up with `KvStoreError::Timeout` if connecting or
+    /// waiting for a response takes longer than `timeout`.
+    pub fn with_timeout(addr: Option<String>, timeout: Option<Duration>) -> Result<Self> {
+        let socket = Stream::connect(&parse_addr(addr)?, timeout).map_err(timed_out)?;
+        socket.set_read_timeout(timeout)?;
+        socket.set_nodelay(true)?;
 
         Ok(Self {
-            addr: sock_addr,
             writer: BufWriter::new(socket.try_clone()?),
             reader: BufReader::new(socket),
         })
     }
 
-    pub fn send(&mut self, mut cmd: Command) -> Result<Response> {
-        let _ = serialize_into(self.writer.get_ref(), &mut cmd)?;
-        let response = deserialize_from::<_, Response>(&mut self.reader)?;
-        println!("{:?}", response);
-        Ok(response)
+    /// Whether commands are sent as soon as they are written, rather than held back by
+    /// Nagle's algorithm to be coalesced with later writes. On by default, since every
+    /// command waits for its response anyway.
+    pub fn set_nodelay(&mut self, nodelay: bool) -> Result<()> {
+        Ok(self.writer.get_ref().set_nodelay(nodelay)?)
+    }
+
+    /// Returns whether Nagle's algorithm is off for the connection, see `set_nodelay`.
+    pub fn nodelay(&self) -> Result<bool> {
+        Ok(self.writer.get_ref().nodelay()?)
+    }
+
+    /// Sends `cmd` and waits for the server's response. Nothing is printed; failures the
+    /// server reports come back as `Response::Err`, which `Response::into_result` turns
+    /// into a `KvStoreError`.
+    pub fn send(&mut self, cmd: Command) -> Result<Response> {
+        write_frame(&mut self.writer, &cmd)?;
+        self.read_response()
+    }
+
+    /// Reads the next frame the server sends.
+    fn read_response(&mut self) -> Result<Response> {
+        read_frame(&mut self.reader).map_err(|err| match err {
+            KvStoreError::IoError(err) => timed_out(err),
+            err => err,
+        })
+    }
+
+    /// Sends `commands` as one `Command::Batch` and returns their responses in order.
+    pub fn send_batch(&mut self, commands: Vec<Command>) -> Result<Vec<Response>> {
+        match self.send(Command::Batch { commands })? {
+            Response::BatchOk(responses) => Ok(responses),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Authenticates with `token`, which must come before any other command on a server
+    /// started with an auth token. A rejected token fails with `KvStoreError::Unauthorized`.
+    pub fn authenticate(&mut self, token: String) -> Result<()> {
+        match self.send(Command::Auth { token })? {
+            Response::AuthOk => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Starts watching `key`. Its changes are then read with `next_change`; other commands
+    /// shouldn't be sent until `unwatch`, as their responses would mix with the changes.
+    pub fn watch(&mut self, key: String) -> Result<()> {
+        match self.send(Command::Watch { key })? {
+            Response::WatchOk => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Waits for the next change to a watched key, returning the key and its new value,
+    /// or `None` if it was removed.
+    pub fn next_change(&mut self) -> Result<(String, Option<String>)> {
+        match self.read_response()? {
+            Response::Changed { key, value } => Ok((key, value)),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Stops every watch, dropping the changes that were sent before the server stopped.
+    pub fn unwatch(&mut self) -> Result<()> {
+        write_frame(&mut self.writer, &Command::Unwatch)?;
+        loop {
+            match self.read_response()? {
+                Response::Changed { .. } => {}
+                Response::UnwatchOk => return Ok(()),
+                response => return Err(unexpected(response)),
+            }
+        }
+    }
+
+    /// Pings the server and returns the round-trip time.
+    pub fn ping(&mut self) -> Result<Duration> {
+        let started = Instant::now();
+        match self.send(Command::Ping)? {
+            Response::Pong => Ok(started.elapsed()),
+            response => Err(unexpected(response)),
+        }
+    }
+}
+
+const DEFAULT_RECONNECT_ATTEMPTS: u32 = 5;
+const DEFAULT_RECONNECT_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(10);
+
+/// A `KvsClient` that survives server restarts: when sending a command fails because the
+/// connection is gone, it reconnects and sends the command once more.
+///
+/// A lost connection leaves it unknown whether the server ran the command before going
+/// away, so a retried command may run twice. Reads like `Get` are unaffected, and so is
+/// `Set`, which writes the same value again. An `Rm` retried after it succeeded fails
+/// with `KeyNotFound`, and commands like `Incr` may be applied twice: retries are
+/// at-least-once, not exactly-once. A timeout is more likely than a dropped connection
+/// to mean the server ran the command, so `Rm`, `Cas`, `Incr` and `RemovePrefix` are
+/// not retried after one.
+///
+/// The auth token and the selected database carry over to a new connection, but watches
+/// don't, so this is for request/response commands only.
+#[derive(Debug)]
+pub struct ReconnectingClient {
+    addr: Option<String>,
+    timeout: Option<Duration>,
+    /// Sent again after every reconnect.
+    token: Option<String>,
+    /// The database of the last accepted `Command::Select`, selected again after every
+    /// reconnect.
+    db: u16,
+    reconnect_attempts: u32,
+    reconnect_backoff: Duration,
+    /// `None` after a failure, until the next command reconnects.
+    client: Option<KvsClient>,
+}
+
+impl ReconnectingClient {
+    /// Connects to `addr` like `KvsClient::new`.
+    pub fn new(addr: Option<String>) -> Result<Self> {
+        Self::with_timeout(addr, None)
+    }
+
+    /// Connects to `addr` like `KvsClient::with_timeout`, which also bounds every
+    /// reconnect.
+    pub fn with_timeout(addr: Option<String>, timeout: Option<Duration>) -> Result<Self> {
+        let client = KvsClient::with_timeout(addr.clone(), timeout)?;
+        Ok(Self {
+            addr,
+            timeout,
+            token: None,
+            db: 0,
+            reconnect_attempts: DEFAULT_RECONNECT_ATTEMPTS,
+            reconnect_backoff: DEFAULT_RECONNECT_BACKOFF,
+            client: Some(client),
+        })
+    }
+
+    /// How many more connection attempts to make after the first one fails before giving
+    /// up, 5 by default.
+    pub fn reconnect_attempts(mut self, attempts: u32) -> Self {
+        self.reconnect_attempts = attempts;
+        self
+    }
+
+    /// How long to wait after the first failed reconnect, 100 ms by default. The wait
+    /// doubles after each further failure, up to 10 s.
+    pub fn reconnect_backoff(mut self, backoff: Duration) -> Self {
+        self.reconnect_backoff = backoff;
+        self
+    }
+
+    /// Authenticates like `KvsClient::authenticate`, and again on every reconnect.
+    pub fn authenticate(&mut self, token: String) -> Result<()> {
+        self.connection()?.authenticate(token.clone())?;
+        self.token = Some(token);
+        Ok(())
+    }
+
+    /// Sends `cmd` and waits for the server's response, reconnecting and sending it once
+    /// more if the connection turns out to be gone. Failures the server reports come back
+    /// as `Response::Err`, as with `KvsClient::send`, and are never retried.
+    pub fn send(&mut self, cmd: Command) -> Result<Response> {
+        let selects = match cmd {
+            Command::Select { db } => Some(db),
+            _ => None,
+        };
+        if let Some(client) = &mut self.client {
+            match client.send(cmd.clone()) {
+                Err(err) if is_connection_lost(&err) => {
+                    self.client = None;
+                    if matches!(err, KvStoreError::Timeout) && !cmd.is_idempotent() {
+                        return Err(err);
+                    }
+                    debug!("Reconnecting after losing the connection: {}", err);
+                }
+                result => return self.record_select(selects, result),
+            }
+        }
+        let result = self.connection()?.send(cmd);
+        if matches!(&result, Err(err) if is_connection_lost(err)) {
+            self.client = None;
+        }
+        self.record_select(selects, result)
+    }
+
+    /// Remembers `selected`, the database a `Command::Select` asked for, if the server
+    /// accepted it.
+    fn record_select(
+        &mut self,
+        selected: Option<u16>,
+        result: Result<Response>,
+    ) -> Result<Response> {
+        if let (Some(db), Ok(Response::SelectOk)) = (selected, &result) {
+            self.db = db;
+        }
+        result
+    }
+
+    /// Returns the open connection, first reconnecting if there is none.
+    fn connection(&mut self) -> Result<&mut KvsClient> {
+        if self.client.is_none() {
+            self.client = Some(self.reconnect()?);
+        }
+        Ok(self.client.as_mut().unwrap())
+    }
+
+    /// Connects, authenticates and selects the database anew, backing off between failed
+    /// attempts.
+    fn reconnect(&self) -> Result<KvsClient> {
+        let mut backoff = self.reconnect_backoff;
+        let mut attempt = 0;
+        loop {
+            let connected = KvsClient::with_timeout(self.addr.clone(), self.timeout)
+                .and_then(|client| self.restore(client));
+            match connected {
+                Err(err) if is_connection_lost(&err) && attempt < self.reconnect_attempts => {
+                    debug!("Failed to reconnect: {}", err);
+                    thread::sleep(backoff);
+                    backoff = backoff.saturating_mul(2).min(MAX_RECONNECT_BACKOFF);
+                    attempt += 1;
+                }
+                connected => return connected,
+            }
+        }
+    }
+
+    /// Brings a new connection to where the lost one was: authenticated, if a token was
+    /// given, and in the selected database.
+    fn restore(&self, mut client: KvsClient) -> Result<KvsClient> {
+        if let Some(token) = &self.token {
+            client.authenticate(token.clone())?;
+        }
+        if self.db != 0 {
+            match client.send(Command::Select { db: self.db })? {
+                Response::SelectOk => {}
+                response => return Err(unexpected(response)),
+            }
+        }
+        Ok(client)
+    }
+}
+
+/// Whether `err` means the connection is unusable, so a new one is worth trying.
+fn is_connection_lost(err: &KvStoreError) -> bool {
+    matches!(err, KvStoreError::IoError(_) | KvStoreError::Timeout)
+}
+
+/// Turns a response other than the one a request expects into an error.
+fn unexpected(response: Response) -> KvStoreError {
+    match response.into_result() {
+        Ok(response) => KvStoreError::ServerError(format!("unexpected response {:?}", response)),
+        Err(err) => err,
+    }
+}
+
+// Read timeouts surface as `WouldBlock` on some platforms and `TimedOut` on others
+fn timed_out(err: io::Error) -> KvStoreError {
+    match err.kind() {
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => KvStoreError::Timeout,
+        _ => err.into(),
     }
 }
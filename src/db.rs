@@ -0,0 +1,76 @@
+use std::{
+    borrow::Cow,
+    ops::{Bound, RangeBounds},
+};
+
+use crate::{kvs_error::Result, KvStoreError};
+
+/// Opens and closes the database number in front of a key in any database but 0, whose
+/// keys are stored as they are. Keys starting with it are reserved.
+const DB_MARKER: char = '\0';
+/// Sorts right after `DB_MARKER`, so it bounds the keys of a database from above.
+const DB_END_MARKER: char = '\u{1}';
+
+/// Fails with `KvStoreError::ReservedKey` if `key` starts with `DB_MARKER`, which would
+/// let it pass for a key of another database. Checked before a key is set.
+pub(crate) fn check_user_key(key: &str) -> Result<()> {
+    if key.starts_with(DB_MARKER) {
+        return Err(KvStoreError::ReservedKey);
+    }
+    Ok(())
+}
+
+/// Returns how `key` of database `db` is stored in the index and the log.
+pub(crate) fn db_key(db: u16, key: String) -> String {
+    if db == 0 {
+        key
+    } else {
+        format!("{}{}", db_prefix(db), key)
+    }
+}
+
+/// Like `db_key`, without copying keys of database 0.
+pub(crate) fn db_key_ref(db: u16, key: &str) -> Cow<'_, str> {
+    if db == 0 {
+        Cow::Borrowed(key)
+    } else {
+        Cow::Owned(db_key(db, key.to_owned()))
+    }
+}
+
+/// Returns the key `stored` as database `db` sees it, or `None` if it belongs to another
+/// database.
+pub(crate) fn user_key(db: u16, stored: &str) -> Option<&str> {
+    let rest = match stored.strip_prefix(DB_MARKER) {
+        Some(rest) => rest,
+        None => return (db == 0).then_some(stored),
+    };
+    let (stored_db, key) = rest.split_once(DB_MARKER)?;
+    (stored_db.parse::<u16>().ok()? == db).then_some(key)
+}
+
+/// Maps a range of database `db`'s keys onto the stored keys. For any database but 0 the
+/// result only covers that database; for database 0 it may also cover others', which
+/// `user_key` filters out.
+pub(crate) fn db_bounds(
+    db: u16,
+    range: &impl RangeBounds<String>,
+) -> (Bound<String>, Bound<String>) {
+    let map = |bound: Bound<&String>, unbounded: Bound<String>| match bound {
+        Bound::Included(key) => Bound::Included(db_key(db, key.clone())),
+        Bound::Excluded(key) => Bound::Excluded(db_key(db, key.clone())),
+        Bound::Unbounded if db == 0 => Bound::Unbounded,
+        Bound::Unbounded => unbounded,
+    };
+    (
+        map(range.start_bound(), Bound::Included(db_prefix(db))),
+        map(
+            range.end_bound(),
+            Bound::Excluded(format!("{}{}{}", DB_MARKER, db, DB_END_MARKER)),
+        ),
+    )
+}
+
+fn db_prefix(db: u16) -> String {
+    format!("{}{}{}", DB_MARKER, db, DB_MARKER)
+}
@@ -1,6 +1,69 @@
-use crate::kvs_error::Result;
-pub trait KvsEngine {
-    fn set(&mut self, key: String, value: String) -> Result<()>;
-    fn get(&mut self, key: String) -> Result<Option<String>>;
-    fn remove(&mut self, key: String) -> Result<()>;
+use crate::{client_commands::CommandPosition, kvs_error::Result, KvStoreError};
+use std::{
+    ops::Bound,
+    sync::{mpsc::Receiver, Arc},
+};
+
+/// A pluggable storage backend for `KvsServer`.
+///
+/// Implementations are internally synchronized, so every operation takes `&self` and an
+/// engine can be shared between threads.
+pub trait KvsEngine: Send + Sync {
+    fn set(&self, key: String, value: String) -> Result<()>;
+
+    /// Sets `key` like `set`, returning where the command landed in the log for engines
+    /// that keep one.
+    fn set_with_position(&self, key: String, value: String) -> Result<Option<CommandPosition>> {
+        self.set(key, value).map(|()| None)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>>;
+    fn remove(&self, key: String) -> Result<()>;
+    fn stats(&self) -> Result<Stats>;
+
+    /// Sets `key` to `new` only if its current value is `expected`, where `None` means
+    /// the key must be absent. Returns whether the value was set.
+    fn compare_and_swap(&self, key: String, expected: Option<String>, new: String) -> Result<bool>;
+
+    /// Adds `delta` to the integer stored at `key`, treating an absent key as 0, and
+    /// returns the sum.
+    fn incr(&self, key: String, delta: i64) -> Result<i64>;
+
+    /// Returns the key/value pairs whose keys start with `prefix`, sorted by key.
+    fn prefix_scan(&self, prefix: &str) -> Result<Vec<(String, String)>>;
+
+    /// Removes every key starting with `prefix`, returning how many there were.
+    fn remove_prefix(&self, prefix: &str) -> Result<usize>;
+
+    /// Returns the key/value pairs whose keys fall between the bounds, sorted by key.
+    fn range(&self, range: (Bound<String>, Bound<String>)) -> Result<Vec<(String, String)>>;
+
+    /// Returns a channel receiving the new value of `key` after every change made through
+    /// this engine or its clones, or `None` when the key is removed. Dropping the receiver
+    /// ends the watch.
+    fn watch(&self, key: String) -> Result<Receiver<Option<String>>>;
+
+    /// Returns once every write made so far has been synced to disk. Engines that keep
+    /// nothing on disk return right away.
+    fn flush_durable(&self) -> Result<()>;
+
+    /// Returns a handle onto the same data that works in database `db`, whose keys are
+    /// kept apart from every other database's. Engines without numbered databases fail
+    /// with `KvStoreError::SingleDatabase`.
+    fn select_db(&self, _db: u16) -> Result<Arc<dyn KvsEngine>> {
+        Err(KvStoreError::SingleDatabase)
+    }
+}
+
+/// A snapshot of an engine's bookkeeping, returned by `KvsEngine::stats`.
+///
+/// Engines that manage their own storage, like sled, report no dirt and no threshold.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stats {
+    pub live_keys: usize,
+    /// Bytes taken by overwritten or removed commands, reclaimed by the next compaction.
+    pub dirt_bytes: u64,
+    /// Size of the engine's files on disk.
+    pub log_size_bytes: u64,
+    pub compaction_threshold: u64,
 }
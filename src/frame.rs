@@ -0,0 +1,61 @@
+use crate::kvs_error::Result;
+use serde::{de::DeserializeOwned, Serialize};
+use std::io::{self, Read, Write};
+
+/// The longest payload a frame may carry. A longer length prefix is rejected before
+/// anything is allocated for it, so a peer can't make the reader allocate at will.
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+/// Writes `message` as a bincode payload prefixed by its length as a 4-byte big-endian
+/// integer, then flushes `writer`. Payloads over `MAX_FRAME_LEN` fail with `InvalidInput`
+/// without writing anything.
+pub fn write_frame<W: Write, T: Serialize>(writer: &mut W, message: &T) -> Result<()> {
+    let payload = bincode::serialize(message)?;
+    if payload.len() > MAX_FRAME_LEN {
+        return Err(frame_too_long(io::ErrorKind::InvalidInput, payload.len()).into());
+    }
+    writer.write_all(&(payload.len() as u32).to_be_bytes())?;
+    writer.write_all(&payload)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads one message written by `write_frame`, consuming exactly its bytes.
+pub fn read_frame<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<T> {
+    try_read_frame(reader)?.ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof).into())
+}
+
+/// Like `read_frame`, but returns `None` if the stream ends cleanly before the frame
+/// starts. A stream ending partway through a frame is still an error, as is a length
+/// over `MAX_FRAME_LEN`, which fails with `InvalidData`.
+pub fn try_read_frame<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<Option<T>> {
+    let mut length = [0; 4];
+    let mut read = 0;
+    while read < length.len() {
+        match reader.read(&mut length[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Ok(bytes) => read += bytes,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    let length = u32::from_be_bytes(length) as usize;
+    if length > MAX_FRAME_LEN {
+        return Err(frame_too_long(io::ErrorKind::InvalidData, length).into());
+    }
+    let mut payload = vec![0; length];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(bincode::deserialize(&payload)?))
+}
+
+fn frame_too_long(kind: io::ErrorKind, length: usize) -> io::Error {
+    io::Error::new(
+        kind,
+        format!(
+            "frame of {} bytes is over the limit of {}",
+            length, MAX_FRAME_LEN
+        ),
+    )
+}
@@ -1,205 +1,2087 @@
-use serde_json::Deserializer;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use fs2::FileExt;
+use log::error;
 
-use crate::{client_commands::CommandPosition, kvs_error::Result, Command, KvStoreError};
+use crate::{
+    bloom::BloomFilter,
+    client_commands::CommandPosition,
+    codec::{JsonCodec, LogCodec},
+    db::{check_user_key, db_bounds, db_key, db_key_ref, user_key},
+    engine::{KvsEngine, Stats},
+    kvs_error::Result,
+    value_cache::ValueCache,
+    watch::Watchers,
+    Command, KvStoreError,
+};
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, HashSet},
     env::current_dir,
     fs::{self, File, OpenOptions},
-    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
-    path::PathBuf,
+    io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    ops::{Bound, RangeBounds},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex, MutexGuard, RwLock, RwLockWriteGuard,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-const THRESHOLD: u64 = 8008135;
+/// The file locked for as long as a store is open, so no other `open` can share the log.
+/// Named stores lock `<name>.LOCK` instead.
+const LOCK_FILE: &str = "LOCK";
+const DEFAULT_COMPACTION_THRESHOLD: u64 = 8008135;
+const DEFAULT_COMPACTION_RATIO: f64 = 0.6;
+/// The same as `BufReader`'s and `BufWriter`'s own default.
+const DEFAULT_BUFFER_CAPACITY: usize = 8 * 1024;
+/// Values shorter than this are stored uncompressed even with `compress_values`.
+const COMPRESSION_MIN_BYTES: usize = 256;
+/// The leading byte of a `SetCompressed` value, saying how the rest is stored.
+const VALUE_RAW: u8 = 0;
+const VALUE_GZIP: u8 = 1;
+
+/// When a `KvStore` compacts its log, set with `KvStoreOptions::compaction_strategy`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompactionStrategy {
+    /// The log is append-only: writes never compact it, `compact_on_open` is ignored, and
+    /// `compact` returns 0 without rewriting anything.
+    Never,
+    /// Compact once a write leaves at least `bytes` stale bytes in the log.
+    OnThreshold { bytes: u64 },
+    /// Compact once stale bytes make up more than `ratio` of the log, however small it is.
+    OnRatio { ratio: f64 },
+    /// Writes never compact the log; only `compact` and `compact_on_open` do.
+    Manual,
+}
+
+impl CompactionStrategy {
+    /// Whether a write leaving `dirt` stale bytes in a log of `log_size` bytes should
+    /// trigger a compaction.
+    fn should_compact(&self, dirt: u64, log_size: u64) -> bool {
+        match *self {
+            CompactionStrategy::Never | CompactionStrategy::Manual => false,
+            CompactionStrategy::OnThreshold { bytes } => dirt >= bytes,
+            CompactionStrategy::OnRatio { ratio } => {
+                dirt > 0 && dirt as f64 > log_size as f64 * ratio
+            }
+        }
+    }
+}
+
+/// Tunables for opening a `KvStore`.
+///
+/// ```rust
+/// # use kvs::{KvStore, KvStoreOptions};
+/// # let dir = tempfile::TempDir::new().unwrap();
+/// let options = KvStoreOptions::default().compaction_threshold(1024);
+/// let store = KvStore::open_with_options(dir.path(), options).unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    compaction_threshold: u64,
+    compaction_ratio: f64,
+    compaction_strategy: Option<CompactionStrategy>,
+    sync_on_write: bool,
+    compact_on_open: bool,
+    max_segment_bytes: u64,
+    max_key_bytes: usize,
+    max_value_bytes: usize,
+    buffer_capacity: usize,
+    keep_generations: usize,
+    value_cache_capacity: usize,
+    bloom_filter_keys: usize,
+    expiry_sweep_interval: Option<Duration>,
+    compress_values: bool,
+    codec: Arc<dyn LogCodec>,
+    log_file_name: String,
+}
+
+impl KvStoreOptions {
+    /// Number of stale bytes in the log after which the store compacts itself.
+    pub fn compaction_threshold(mut self, bytes: u64) -> Self {
+        self.compaction_threshold = bytes;
+        self
+    }
+
+    /// Fraction of the log taken by stale bytes after which the store compacts itself,
+    /// however small the log is.
+    pub fn compaction_ratio(mut self, ratio: f64) -> Self {
+        self.compaction_ratio = ratio;
+        self
+    }
+
+    /// Replaces the default of compacting on either `compaction_threshold` or
+    /// `compaction_ratio`, whichever is reached first, with a single `strategy`.
+    pub fn compaction_strategy(mut self, strategy: CompactionStrategy) -> Self {
+        self.compaction_strategy = Some(strategy);
+        self
+    }
+
+    /// Whether to gzip string values before writing them. Reads decompress transparently,
+    /// so a store can be reopened with either setting.
+    ///
+    /// Short values, and values that don't shrink, are stored as they are.
+    pub fn compress_values(mut self, compress: bool) -> Self {
+        self.compress_values = compress;
+        self
+    }
+
+    /// How commands are encoded in the log, `JsonCodec` by default.
+    ///
+    /// A store must always be reopened with the codec it was created with.
+    pub fn codec(mut self, codec: impl LogCodec + 'static) -> Self {
+        self.codec = Arc::new(codec);
+        self
+    }
+
+    /// Whether `open` compacts the log once it has been loaded, trading a slower start
+    /// for reclaiming the space taken by overwritten and removed commands.
+    pub fn compact_on_open(mut self, compact: bool) -> Self {
+        self.compact_on_open = compact;
+        self
+    }
+
+    /// Size after which writes roll over from the active log file to a new generation,
+    /// unlimited by default. A single record is never split, so a file can exceed this by
+    /// up to one record, and compaction still writes all live records to one file.
+    pub fn max_segment_bytes(mut self, bytes: u64) -> Self {
+        self.max_segment_bytes = bytes;
+        self
+    }
+
+    /// Longest key, in bytes, that writes accept, unlimited by default. Longer keys fail
+    /// with `KvStoreError::KeyTooLarge` before anything reaches the log.
+    pub fn max_key_bytes(mut self, bytes: usize) -> Self {
+        self.max_key_bytes = bytes;
+        self
+    }
+
+    /// Longest value, in bytes before any compression, that writes accept, unlimited by
+    /// default. Longer values fail with `KvStoreError::ValueTooLarge` before anything
+    /// reaches the log.
+    pub fn max_value_bytes(mut self, bytes: usize) -> Self {
+        self.max_value_bytes = bytes;
+        self
+    }
+
+    /// Size of the buffers in front of each log file, for both reading and writing, 8 KiB
+    /// by default. Larger buffers mean fewer system calls for bulk loads and long scans,
+    /// at the cost of memory per open generation.
+    pub fn buffer_capacity(mut self, bytes: usize) -> Self {
+        self.buffer_capacity = bytes;
+        self
+    }
+
+    /// Number of generations superseded by compaction to keep, as `<gen>.log.old` next to
+    /// the log, for a later restore. The oldest are deleted beyond that; none are kept by
+    /// default.
+    pub fn keep_generations(mut self, generations: usize) -> Self {
+        self.keep_generations = generations;
+        self
+    }
+
+    /// Keeps the values of up to `entries` recently read keys in memory, so repeated reads
+    /// of hot keys skip the log. Off by default. Values with a TTL are never cached.
+    pub fn value_cache_capacity(mut self, entries: usize) -> Self {
+        self.value_cache_capacity = entries;
+        self
+    }
+
+    /// Keeps a bloom filter over the keys, sized for `expected_keys`, so reads of absent
+    /// keys are mostly answered without probing the index. Off by default.
+    ///
+    /// Removed keys stay in the filter until compaction rebuilds it, which also resizes
+    /// it if the store has outgrown `expected_keys`.
+    pub fn bloom_filter(mut self, expected_keys: usize) -> Self {
+        self.bloom_filter_keys = expected_keys;
+        self
+    }
+
+    /// Starts a background thread that calls `remove_expired` every `interval`, so keys
+    /// set with a TTL are reclaimed even if never read again. Off by default; read-only
+    /// stores never sweep.
+    pub fn expiry_sweep_interval(mut self, interval: Duration) -> Self {
+        self.expiry_sweep_interval = Some(interval);
+        self
+    }
+
+    /// Names the store's files `<name>.<gen>.log` instead of `<gen>.log`, so several
+    /// stores can share a directory. Each name is locked separately.
+    ///
+    /// A store must always be reopened with the name it was created with.
+    pub fn log_file_name(mut self, name: impl Into<String>) -> Self {
+        self.log_file_name = name.into();
+        self
+    }
+
+    /// Whether to `fsync` the log after every write.
+    ///
+    /// This makes each successful write durable across power loss, at the cost of a disk
+    /// round trip per write, which lowers write throughput considerably.
+    pub fn sync_on_write(mut self, sync: bool) -> Self {
+        self.sync_on_write = sync;
+        self
+    }
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        Self {
+            compaction_threshold: DEFAULT_COMPACTION_THRESHOLD,
+            compaction_ratio: DEFAULT_COMPACTION_RATIO,
+            compaction_strategy: None,
+            sync_on_write: false,
+            compact_on_open: false,
+            max_segment_bytes: u64::MAX,
+            max_key_bytes: usize::MAX,
+            max_value_bytes: usize::MAX,
+            buffer_capacity: DEFAULT_BUFFER_CAPACITY,
+            keep_generations: 0,
+            value_cache_capacity: 0,
+            bloom_filter_keys: 0,
+            expiry_sweep_interval: None,
+            compress_values: false,
+            codec: Arc::new(JsonCodec),
+            log_file_name: String::new(),
+        }
+    }
+}
 
 /// The `KvStore` stores string key/value pairs.
 ///
-/// Key/value pairs are stored in a `HashMap` in memory and not persisted to disk.
+/// Commands are appended to numbered log files (`1.log`, `2.log`, ...) inside the store
+/// directory, and an in-memory index maps each live key to the generation and position
+/// of its latest `Set` command.
 ///
 /// Example:
 ///
 /// ```rust
-/// # use kvs::KvStore;
-/// let mut store = KvStore::open("").unwrap();
+/// # use kvs::{KvStore, KvsEngine};
+/// # let dir = tempfile::TempDir::new().unwrap();
+/// let store = KvStore::open(dir.path()).unwrap();
 /// store.set("key".to_owned(), "value".to_owned()).unwrap();
 /// let val = store.get("key".to_owned()).unwrap();
 /// assert_eq!(val, Some("value".to_owned()));
 /// ```
+///
+/// Clones share the writer and the index, while each clone reads through its own file
+/// handles, so a `KvStore` can be cloned into every thread that serves requests.
+///
+/// The store holds an exclusive lock on a `LOCK` file in its directory until the last
+/// clone is dropped, so opening the same directory twice fails with
+/// `KvStoreError::AlreadyLocked`. Stores opened with `open_read_only` take no lock.
+///
+/// Each handle reads and writes within one of 65536 numbered databases, 0 unless chosen
+/// with `with_db`. Keys of different databases never collide, though they share the log,
+/// so `stats`, `compact` and `snapshot` cover every database at once. Keys starting with
+/// a NUL character are reserved for this, and setting one fails with
+/// `KvStoreError::ReservedKey`.
 #[derive(Debug)]
 pub struct KvStore {
-    pub path: PathBuf,
-    pub writer: BufWriterWithPos<File>,
-    reader: BufReaderWithPos<File>,
-    pub index: BTreeMap<String, CommandPosition>,
+    path: Arc<StorePath>,
+    /// Released when the last clone drops it. `None` for a read-only store.
+    lock: Option<Arc<File>>,
+    /// `None` for a read-only store.
+    writer: Option<Arc<Mutex<KvStoreWriter>>>,
+    reader: Mutex<KvStoreReader>,
+    index: Arc<RwLock<BTreeMap<String, CommandPosition>>>,
+    options: KvStoreOptions,
+    metrics: Arc<MetricsCounters>,
+    watchers: Watchers,
+    /// Shared by every clone. `None` unless `value_cache_capacity` is set.
+    value_cache: Option<Arc<Mutex<ValueCache>>>,
+    /// Shared by every clone and only written to under the index's write lock. `None`
+    /// unless `bloom_filter` is set.
+    bloom_filter: Option<Arc<RwLock<BloomFilter>>>,
+    /// Stopped when the last clone drops it. `None` unless `expiry_sweep_interval` is set.
+    sweeper: Option<Arc<Sweeper>>,
+    /// The database this handle works in, copied by clones.
+    db: u16,
+}
+
+impl Clone for KvStore {
+    fn clone(&self) -> Self {
+        Self {
+            path: self.path.clone(),
+            lock: self.lock.clone(),
+            writer: self.writer.clone(),
+            reader: Mutex::new(self.reader.lock().unwrap().clone()),
+            index: self.index.clone(),
+            options: self.options.clone(),
+            metrics: self.metrics.clone(),
+            watchers: self.watchers.clone(),
+            value_cache: self.value_cache.clone(),
+            bloom_filter: self.bloom_filter.clone(),
+            sweeper: self.sweeper.clone(),
+            db: self.db,
+        }
+    }
+}
+
+/// Flushes whatever the writer still buffers. Errors can't be returned from here, so
+/// they are only logged; call `close` to see them.
+impl Drop for KvStore {
+    fn drop(&mut self) {
+        if let Some(writer) = &self.writer {
+            if let Ok(mut writer) = writer.lock() {
+                if let Err(err) = self.flush(&mut writer) {
+                    error!("Failed to flush the log on drop: {}", err);
+                }
+            }
+        }
+    }
+}
+
+/// What `KvStore::verify` found wrong with a store. Everything is empty for a sound one.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Number of complete records found across every generation.
+    pub records: u64,
+    /// Records that fail to decode, as the generation and offset they start at.
+    pub corrupt_records: Vec<(u64, u64)>,
+    /// Keys whose index entry doesn't point at a readable `Set` of that key.
+    pub mismatched_keys: Vec<String>,
+}
+
+impl VerifyReport {
+    /// Returns whether the log and the index check out.
+    pub fn is_ok(&self) -> bool {
+        self.corrupt_records.is_empty() && self.mismatched_keys.is_empty()
+    }
+}
+
+/// Operation counts since a store was opened, as returned by `KvStore::metrics`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Metrics {
+    /// Single-key reads, counting each key of a `get_many`.
+    pub gets: u64,
+    /// Writes of a value, counting each distinct key of a `set_many`.
+    pub sets: u64,
+    /// Keys removed. Removing an absent key doesn't count.
+    pub removes: u64,
+    pub compactions: u64,
+    /// Gets that found a live value.
+    pub cache_hits: u64,
+    /// Gets that found the key absent or expired.
+    pub misses: u64,
+    /// Gets served from the value cache without reading the log.
+    pub value_cache_hits: u64,
+    /// Gets the bloom filter answered as absent without probing the index.
+    pub bloom_filter_skips: u64,
+}
+
+/// The counters behind `Metrics`, shared by every clone of a store.
+#[derive(Debug, Default)]
+struct MetricsCounters {
+    gets: AtomicU64,
+    sets: AtomicU64,
+    removes: AtomicU64,
+    compactions: AtomicU64,
+    cache_hits: AtomicU64,
+    misses: AtomicU64,
+    value_cache_hits: AtomicU64,
+    bloom_filter_skips: AtomicU64,
+}
+
+impl MetricsCounters {
+    fn record_get(&self, found: bool) {
+        self.gets.fetch_add(1, Ordering::Relaxed);
+        if found {
+            self.cache_hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn add(counter: &AtomicU64, count: usize) {
+        counter.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> Metrics {
+        Metrics {
+            gets: self.gets.load(Ordering::Relaxed),
+            sets: self.sets.load(Ordering::Relaxed),
+            removes: self.removes.load(Ordering::Relaxed),
+            compactions: self.compactions.load(Ordering::Relaxed),
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            value_cache_hits: self.value_cache_hits.load(Ordering::Relaxed),
+            bloom_filter_skips: self.bloom_filter_skips.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The thread behind `expiry_sweep_interval`, calling `remove_expired` on a clone of the
+/// store. Dropping it stops the thread and waits for it, so the store's lock is released
+/// by the time the last clone is gone.
+#[derive(Debug)]
+struct Sweeper {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Sweeper {
+    /// Sweeps `store` every `interval`. `store` must have no sweeper of its own, or the
+    /// thread would keep itself alive.
+    fn spawn(store: KvStore, interval: Duration) -> Self {
+        let (stop, stopped) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                if let Err(err) = store.remove_expired() {
+                    error!("Failed to sweep expired keys: {}", err);
+                }
+            }
+        });
+        Self {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for Sweeper {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// What `KvStore::compaction_estimate` expects a compaction to achieve.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionEstimate {
+    /// Size of every generation on disk.
+    pub current_bytes: u64,
+    /// Size of the live records, i.e. of the log once compacted.
+    pub live_bytes: u64,
+    /// What compacting would free: `current_bytes` minus `live_bytes`.
+    pub reclaimable_bytes: u64,
+}
+
+/// What `KvStore::open_with_report` noticed while replaying the log.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OpenReport {
+    /// `Rm` records for keys that weren't set at that point in the log. Removing a key
+    /// whose TTL had already run out leaves one of these too.
+    pub dangling_removes: u64,
+}
+
+/// The state only touched while appending to the active generation.
+#[derive(Debug)]
+struct KvStoreWriter {
+    gen: u64,
+    writer: BufWriterWithPos<File>,
     dirt: u64,
+    /// Combined size of every generation, kept here so writes needn't stat the files.
+    log_size: u64,
+    /// When each key last set with a TTL expires, so the sweeper only reads their
+    /// records. Entries outlive a later `Set` or `Rm` of their key until they come due.
+    expiring: BTreeMap<String, u64>,
+}
+
+/// Per-clone read handles onto the generation files, opened on first use.
+#[derive(Debug)]
+struct KvStoreReader {
+    path: Arc<StorePath>,
+    /// Generations below this one have been compacted away and can be closed.
+    safe_point: Arc<AtomicU64>,
+    readers: BTreeMap<u64, BufReaderWithPos<File>>,
+    codec: Arc<dyn LogCodec>,
+    buffer_capacity: usize,
+}
+
+impl Clone for KvStoreReader {
+    fn clone(&self) -> Self {
+        Self {
+            path: self.path.clone(),
+            safe_point: self.safe_point.clone(),
+            readers: BTreeMap::new(),
+            codec: self.codec.clone(),
+            buffer_capacity: self.buffer_capacity,
+        }
+    }
+}
+
+impl KvStoreReader {
+    /// Returns the reader for `gen`, closing the handles of compacted generations.
+    fn reader(&mut self, gen: u64) -> Result<&mut BufReaderWithPos<File>> {
+        let safe_point = self.safe_point.load(Ordering::SeqCst);
+        self.readers
+            .retain(|&reader_gen, _| reader_gen >= safe_point);
+
+        if !self.readers.contains_key(&gen) {
+            let file = File::open(log_path(&self.path, gen))?;
+            let reader = BufReaderWithPos::with_capacity(self.buffer_capacity, file);
+            self.readers.insert(gen, reader);
+        }
+        Ok(self.readers.get_mut(&gen).unwrap())
+    }
+
+    /// Reads the command stored at `cmd_position`.
+    fn read_command(&mut self, cmd_position: &CommandPosition) -> Result<Command> {
+        let reader = self.reader(cmd_position.gen)?;
+        reader.seek(SeekFrom::Start(cmd_position.start))?;
+        let mut record = vec![0; cmd_position.length as usize];
+        reader.read_exact(&mut record)?;
+        self.codec.decode(&record, cmd_position.start)
+    }
+
+    /// Reads the value stored at `cmd_position` as a string, or `None` if it has expired.
+    fn read_value(&mut self, cmd_position: &CommandPosition) -> Result<Option<String>> {
+        command_value(self.read_command(cmd_position)?)
+    }
+}
+
+impl KvsEngine for KvStore {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_with_position(key, value).map(|_| ())
+    }
+
+    fn set_with_position(&self, key: String, value: String) -> Result<Option<CommandPosition>> {
+        check_user_key(&key)?;
+        let mut writer = self.lock_writer()?;
+        let command = self.set_command(db_key(self.db, key), value)?;
+        let cmd_position = self.append_set(&mut writer, command)?;
+        self.compact_if_needed(writer)?;
+        Ok(Some(cmd_position))
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let key = db_key(self.db, key);
+        if self.skip_absent(&key) {
+            return Ok(None);
+        }
+        if self.value_cache.is_some() {
+            return Ok(self.read_shared(&key)?.map(|value| value.to_string()));
+        }
+        let index = self.index.read().unwrap();
+        let cmd_position = match index.get(&key) {
+            Some(cmd_position) => cmd_position,
+            None => {
+                self.metrics.record_get(false);
+                return Ok(None);
+            }
+        };
+
+        let value = self.reader.lock().unwrap().read_value(cmd_position)?;
+        self.metrics.record_get(value.is_some());
+        if value.is_none() {
+            let (gen, start) = (cmd_position.gen, cmd_position.start);
+            drop(index);
+            self.forget_expired(&key, gen, start);
+        }
+        Ok(value)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        if self.remove_if_exists(key)? {
+            Ok(())
+        } else {
+            Err(KvStoreError::KeyNotFound)
+        }
+    }
+
+    fn compare_and_swap(&self, key: String, expected: Option<String>, new: String) -> Result<bool> {
+        check_user_key(&key)?;
+        let key = db_key(self.db, key);
+        let mut writer = self.lock_writer()?;
+        if self.current_value(&key)? != expected {
+            return Ok(false);
+        }
+        self.append_set(&mut writer, self.set_command(key, new)?)?;
+        self.compact_if_needed(writer)?;
+        Ok(true)
+    }
+
+    fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        check_user_key(&key)?;
+        let key = db_key(self.db, key);
+        let mut writer = self.lock_writer()?;
+        let value = add_to_integer(self.current_value(&key)?, delta)?;
+        self.append_set(&mut writer, self.set_command(key, value.to_string())?)?;
+        self.compact_if_needed(writer)?;
+        Ok(value)
+    }
+
+    fn prefix_scan(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        let index = self.index.read().unwrap();
+        let mut reader = self.reader.lock().unwrap();
+        self.db_range(&index, &(prefix.to_owned()..))
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .filter_map(|(key, cmd_position)| {
+                let value = reader.read_value(cmd_position).transpose()?;
+                Some(value.map(|value| (key.to_owned(), value)))
+            })
+            .collect()
+    }
+
+    fn remove_prefix(&self, prefix: &str) -> Result<usize> {
+        let writer = self.lock_writer()?;
+        let index = self.index.write().unwrap();
+        let prefix = db_key(self.db, prefix.to_owned());
+        let keys: Vec<String> = index
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .filter(|(key, _)| user_key(self.db, key).is_some())
+            .map(|(key, _)| key.clone())
+            .collect();
+        self.remove_keys(writer, index, keys)
+    }
+
+    fn range(&self, range: (Bound<String>, Bound<String>)) -> Result<Vec<(String, String)>> {
+        // Resolves to the inherent, generic `range`
+        KvStore::range(self, range)
+    }
+
+    fn watch(&self, key: String) -> Result<Receiver<Option<String>>> {
+        Ok(self.watchers.subscribe(db_key(self.db, key)))
+    }
+
+    /// Flushes and syncs the active generation, whatever `sync_on_write` says. Older
+    /// generations were synced when writes rolled over from them, or by the compaction
+    /// that wrote them. A read-only store has nothing to sync.
+    fn flush_durable(&self) -> Result<()> {
+        if let Some(writer) = &self.writer {
+            let mut writer = writer.lock().unwrap();
+            writer.writer.flush()?;
+            writer.writer.source.get_ref().sync_all()?;
+        }
+        Ok(())
+    }
+
+    fn select_db(&self, db: u16) -> Result<Arc<dyn KvsEngine>> {
+        let mut store = self.clone();
+        store.with_db(db);
+        Ok(Arc::new(store))
+    }
+
+    /// Reports the key count, the stale bytes and the log size, which help decide when
+    /// to call `compact`.
+    fn stats(&self) -> Result<Stats> {
+        let writer = self.writer.as_ref().map(|writer| writer.lock().unwrap());
+        let live_keys = self.index.read().unwrap().len();
+        Ok(Stats {
+            live_keys,
+            dirt_bytes: writer.map_or(0, |writer| writer.dirt),
+            log_size_bytes: log_size(&self.path)?,
+            compaction_threshold: match self.options.compaction_strategy {
+                None => self.options.compaction_threshold,
+                Some(CompactionStrategy::OnThreshold { bytes }) => bytes,
+                Some(_) => 0,
+            },
+        })
+    }
 }
 
 impl KvStore {
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        let command = Command::Set {
-            key: key.clone(),
+    /// Switches this handle to database `db`, leaving other clones where they are.
+    pub fn with_db(&mut self, db: u16) -> &mut Self {
+        self.db = db;
+        self
+    }
+
+    /// Returns the database this handle works in.
+    pub fn db(&self) -> u16 {
+        self.db
+    }
+
+    /// Sets `key` to `value` and returns the value it replaced, if any.
+    pub fn set_and_get(&self, key: String, value: String) -> Result<Option<String>> {
+        check_user_key(&key)?;
+        let key = db_key(self.db, key);
+        let mut writer = self.lock_writer()?;
+        let old_value = self.current_value(&key)?;
+        self.append_set(&mut writer, self.set_command(key, value)?)?;
+        self.compact_if_needed(writer)?;
+        Ok(old_value)
+    }
+
+    /// Returns the value of `key`, first setting it to the result of `f` if it is absent.
+    ///
+    /// `f` runs only on a miss, under the writer lock, so it must not use the store.
+    pub fn get_or_insert_with(&self, key: String, f: impl FnOnce() -> String) -> Result<String> {
+        check_user_key(&key)?;
+        let key = db_key(self.db, key);
+        let mut writer = self.lock_writer()?;
+        if let Some(value) = self.current_value(&key)? {
+            return Ok(value);
+        }
+
+        let value = f();
+        self.append_set(&mut writer, self.set_command(key, value.clone())?)?;
+        self.compact_if_needed(writer)?;
+        Ok(value)
+    }
+
+    /// Removes `key` if it is present, returning whether it was. Unlike `remove`, an absent
+    /// key is not an error.
+    pub fn remove_if_exists(&self, key: String) -> Result<bool> {
+        let key = db_key(self.db, key);
+        let mut writer = self.lock_writer()?;
+        if !self.index.read().unwrap().contains_key(&key) {
+            return Ok(false);
+        }
+
+        // Only dropped from the index once the `Rm` is in the log, so a failed write
+        // leaves the key readable, as it will be after reopening
+        let rm_position = self.write_command(&mut writer, &Command::Rm { key: key.clone() })?;
+        self.flush(&mut writer)?;
+        let mut index = self.index.write().unwrap();
+        if let Some(cmd_position) = index.remove(&key) {
+            // Both the removed `Set` and the `Rm` itself are dead once compacted
+            writer.dirt += cmd_position.length;
+        }
+        writer.dirt += rm_position.length;
+        self.forget_cached(&key);
+        drop(index);
+        MetricsCounters::add(&self.metrics.removes, 1);
+        self.watchers.notify(&key, || None);
+        self.compact_if_needed(writer)?;
+        Ok(true)
+    }
+
+    /// Sets every pair in `pairs`, in order, with a single flush at the end. A key given
+    /// more than once ends up with its last value, and only that one is written.
+    ///
+    /// The index is only updated once the whole batch has been flushed.
+    pub fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let mut seen = HashSet::with_capacity(pairs.len());
+        let mut pairs: Vec<(String, String)> = pairs
+            .into_iter()
+            .rev()
+            .filter(|(key, _)| seen.insert(key.clone()))
+            .collect();
+        pairs.reverse();
+
+        let mut writer = self.lock_writer()?;
+        // Checked up front, so a rejected pair doesn't leave the ones before it in the log
+        for (key, value) in &pairs {
+            check_user_key(key)?;
+            self.check_size(key, value.len())?;
+        }
+        let mut positions = Vec::with_capacity(pairs.len());
+        for (key, value) in pairs {
+            let key = db_key(self.db, key);
+            let command = self.set_command(key.clone(), value)?;
+            let cmd_position = self.write_command(&mut writer, &command)?;
+            positions.push((key, cmd_position, command));
+        }
+        self.flush(&mut writer)?;
+
+        MetricsCounters::add(&self.metrics.sets, positions.len());
+        for (key, cmd_position, command) in positions {
+            self.index_set(&mut writer, key.clone(), cmd_position);
+            self.notify_set(&key, command);
+        }
+        self.compact_if_needed(writer)
+    }
+
+    /// Sets `key` to `value` until `ttl` has passed, after which reads treat it as removed.
+    ///
+    /// The expiry is stored in the log as a wall-clock time, so it survives reopening.
+    pub fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        check_user_key(&key)?;
+        self.check_size(&key, value.len())?;
+        let mut writer = self.lock_writer()?;
+        let command = Command::SetWithTtl {
+            key: db_key(self.db, key),
             value,
+            expires_at_unix_ms: now_unix_ms().saturating_add(ttl.as_millis() as u64),
+        };
+        self.append_set(&mut writer, command)?;
+        self.compact_if_needed(writer)
+    }
+
+    /// Sets `key` to an arbitrary byte value.
+    pub fn set_bytes(&self, key: String, value: Vec<u8>) -> Result<()> {
+        check_user_key(&key)?;
+        self.check_size(&key, value.len())?;
+        let mut writer = self.lock_writer()?;
+        let key = db_key(self.db, key);
+        self.append_set(&mut writer, Command::SetBytes { key, value })?;
+        self.compact_if_needed(writer)
+    }
+
+    /// Gets the value of `key` as a shared string. With `value_cache_capacity` set, a
+    /// cached value is returned without reading the log or copying it.
+    pub fn get_shared(&self, key: &str) -> Result<Option<Arc<str>>> {
+        self.read_shared(&db_key_ref(self.db, key))
+    }
+
+    /// Reads the value of the stored `key` for `get_shared`, caching it if it may be.
+    fn read_shared(&self, key: &str) -> Result<Option<Arc<str>>> {
+        if self.skip_absent(key) {
+            return Ok(None);
+        }
+        let index = self.index.read().unwrap();
+        let cmd_position = match index.get(key) {
+            Some(cmd_position) => *cmd_position,
+            None => {
+                self.metrics.record_get(false);
+                return Ok(None);
+            }
         };
+        if let Some(value) = self
+            .value_cache
+            .as_ref()
+            .and_then(|cache| cache.lock().unwrap().get(key))
+        {
+            self.metrics.record_get(true);
+            MetricsCounters::add(&self.metrics.value_cache_hits, 1);
+            return Ok(Some(value));
+        }
 
-        let curr_position = self.writer.position;
-        serde_json::to_writer(&mut self.writer, &command)?;
-        self.writer.flush()?;
-        if let Some(old_value) = self.index.insert(
-            key,
-            CommandPosition {
-                start: curr_position,
-                length: self.writer.position - curr_position,
+        let command = self.reader.lock().unwrap().read_command(&cmd_position)?;
+        let expires = matches!(command, Command::SetWithTtl { .. });
+        let value: Option<Arc<str>> = command_value(command)?.map(Arc::from);
+        self.metrics.record_get(value.is_some());
+        match (&value, &self.value_cache) {
+            // Cached while the index is still locked, so a write to `key` can't slip in
+            // between reading the value and caching it
+            (Some(value), Some(cache)) if !expires => {
+                cache.lock().unwrap().insert(key.to_owned(), value.clone());
+            }
+            (None, _) => {
+                drop(index);
+                self.forget_expired(key, cmd_position.gen, cmd_position.start);
+            }
+            _ => {}
+        }
+        Ok(value)
+    }
+
+    /// Gets the value of `key` as bytes. Values stored with `set` are returned as their
+    /// UTF-8 encoding.
+    pub fn get_bytes(&self, key: String) -> Result<Option<Vec<u8>>> {
+        let index = self.index.read().unwrap();
+        let value = match index.get(&db_key(self.db, key)) {
+            Some(cmd_position) => match self.reader.lock().unwrap().read_command(cmd_position)? {
+                Command::Set { value, .. } => Some(value.into_bytes()),
+                Command::SetBytes { value, .. } => Some(value),
+                Command::SetCompressed { value, .. } => Some(decompress_value(&value)?),
+                Command::SetWithTtl {
+                    expires_at_unix_ms, ..
+                } if expires_at_unix_ms <= now_unix_ms() => None,
+                Command::SetWithTtl { value, .. } => Some(value.into_bytes()),
+                _ => return Err(KvStoreError::InvalidLogFileCommand),
             },
-        ) {
-            self.dirt += old_value.length;
+            None => None,
+        };
+        self.metrics.record_get(value.is_some());
+        Ok(value)
+    }
+
+    /// Gets the values of `keys`, in the same order, with `None` for absent keys.
+    ///
+    /// The log is read in file order rather than key order, so lookups scattered across
+    /// the log seek forward only.
+    pub fn get_many(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        let keys: Vec<String> = keys
+            .iter()
+            .map(|key| db_key(self.db, key.clone()))
+            .collect();
+        let index = self.index.read().unwrap();
+        let mut lookups: Vec<(usize, CommandPosition)> = keys
+            .iter()
+            .enumerate()
+            .filter_map(|(i, key)| index.get(key).map(|cmd_position| (i, *cmd_position)))
+            .collect();
+        lookups.sort_unstable_by_key(|(_, cmd_position)| (cmd_position.gen, cmd_position.start));
+
+        let mut values = vec![None; keys.len()];
+        let mut expired = Vec::new();
+        let mut reader = self.reader.lock().unwrap();
+        for (i, cmd_position) in lookups {
+            match reader.read_value(&cmd_position)? {
+                Some(value) => values[i] = Some(value),
+                None => expired.push((i, cmd_position)),
+            }
+        }
+        drop(reader);
+        drop(index);
+        for value in &values {
+            self.metrics.record_get(value.is_some());
         }
 
-        if self.dirt >= THRESHOLD {
-            self.compact()?;
-            self.dirt = 0;
+        for (i, cmd_position) in expired {
+            self.forget_expired(&keys[i], cmd_position.gen, cmd_position.start);
         }
+        Ok(values)
+    }
+
+    /// Returns the operation counts since the store was opened, summed over every clone.
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.snapshot()
+    }
+
+    /// Returns whether `key` is live, consulting only the in-memory index.
+    pub fn contains_key(&self, key: &str) -> bool {
+        let key = db_key_ref(self.db, key);
+        !self.definitely_absent(&key) && self.index.read().unwrap().contains_key(key.as_ref())
+    }
 
+    /// Returns the number of live keys in the selected database.
+    pub fn len(&self) -> usize {
+        self.db_range(&self.index.read().unwrap(), &(..)).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.db_range(&self.index.read().unwrap(), &(..))
+            .next()
+            .is_none()
+    }
+
+    /// Returns the live keys of the selected database in ascending order without reading
+    /// any values.
+    ///
+    /// The index sits behind a lock, so this iterates over a snapshot taken at call time.
+    pub fn keys(&self) -> impl Iterator<Item = String> {
+        let keys: Vec<String> = self
+            .db_range(&self.index.read().unwrap(), &(..))
+            .map(|(key, _)| key.to_owned())
+            .collect();
+        keys.into_iter()
+    }
+
+    /// Returns the key/value pairs whose keys fall within `range`, sorted by key.
+    pub fn range(&self, range: impl RangeBounds<String>) -> Result<Vec<(String, String)>> {
+        if is_empty_range(&range) {
+            return Ok(Vec::new());
+        }
+
+        let index = self.index.read().unwrap();
+        let mut reader = self.reader.lock().unwrap();
+        self.db_range(&index, &range)
+            .filter_map(|(key, cmd_position)| {
+                let value = reader.read_value(cmd_position).transpose()?;
+                Some(value.map(|value| (key.to_owned(), value)))
+            })
+            .collect()
+    }
+
+    /// Iterates over the entries of `index` within `range` of the selected database, by
+    /// the keys that database knows them as.
+    fn db_range<'a>(
+        &self,
+        index: &'a BTreeMap<String, CommandPosition>,
+        range: &impl RangeBounds<String>,
+    ) -> impl Iterator<Item = (&'a str, &'a CommandPosition)> {
+        let db = self.db;
+        index
+            .range(db_bounds(db, range))
+            .filter_map(move |(key, cmd_position)| Some((user_key(db, key)?, cmd_position)))
+    }
+
+    /// Returns every live key/value pair, sorted by key.
+    ///
+    /// All values are read into memory at once. To walk a large store in bounded memory,
+    /// take the `keys` snapshot and read them in chunks with `range`.
+    pub fn entries(&self) -> Result<Vec<(String, String)>> {
+        self.range(..)
+    }
+
+    /// Writes every live key/value pair to `writer` as one JSON object, sorted by key.
+    pub fn export(&self, writer: impl Write) -> Result<()> {
+        let pairs: BTreeMap<String, String> = self.range(..)?.into_iter().collect();
+        serde_json::to_writer(writer, &pairs)?;
         Ok(())
     }
 
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        if let Some(cmd_position) = self.index.get(&key) {
-            let reader = self.reader.source.get_mut();
-            reader
-                .seek(SeekFrom::Start(cmd_position.start))
-                .expect("Couldn't get mutable reference to reader");
-            let taken = reader.take(cmd_position.length);
-            if let Command::Set { value, key: _ } = serde_json::from_reader(taken)? {
-                return Ok(Some(value));
-            } else {
-                return Err(KvStoreError::InvalidLogFileCommand);
+    /// Sets every pair in a JSON object written by `export`, returning how many there were.
+    pub fn import(&self, reader: impl Read) -> Result<usize> {
+        let pairs: BTreeMap<String, String> = serde_json::from_reader(reader)?;
+        let count = pairs.len();
+        self.set_many(pairs.into_iter().collect())?;
+        Ok(count)
+    }
+
+    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        Self::open_with_options(path, KvStoreOptions::default())
+    }
+
+    pub fn open_with_options(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
+        Self::open_with_report(path, options).map(|(store, _)| store)
+    }
+
+    /// Opens (or creates) the store at `path` and sets every pair of `pairs`, in order,
+    /// with a single flush at the end.
+    pub fn open_from_iter(
+        path: impl Into<PathBuf>,
+        pairs: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<KvStore> {
+        let store = Self::open(path)?;
+        store.set_many(pairs.into_iter().collect())?;
+        Ok(store)
+    }
+
+    /// Opens the store like `open_with_options`, also returning what replaying the log
+    /// turned up, to help debug a damaged or hand-edited log.
+    pub fn open_with_report(
+        path: impl Into<PathBuf>,
+        options: KvStoreOptions,
+    ) -> Result<(KvStore, OpenReport)> {
+        Self::open_store(path.into(), options, false)
+    }
+
+    /// Opens an existing store for reading only, for example to back it up while another
+    /// process writes to it.
+    ///
+    /// The index reflects the log at the time of opening. Writes, `clear` and `compact`
+    /// fail with `KvStoreError::ReadOnly`, and nothing in the directory is created or
+    /// modified, so a directory without log files is an error.
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<KvStore> {
+        Self::open_store(path.into(), KvStoreOptions::default(), true).map(|(store, _)| store)
+    }
+
+    fn open_store(
+        mut dir: PathBuf,
+        options: KvStoreOptions,
+        read_only: bool,
+    ) -> Result<(KvStore, OpenReport)> {
+        if dir.as_os_str().is_empty() {
+            dir = current_dir()?;
+        }
+        let path = StorePath {
+            dir,
+            name: options.log_file_name.clone(),
+        };
+        let lock = if read_only {
+            if !path.dir.is_dir() {
+                return Err(KvStoreError::InvalidPath {
+                    path: path.dir,
+                    source: io::ErrorKind::NotFound.into(),
+                });
             }
+            None
         } else {
-            return Ok(None);
+            if let Err(source) = fs::create_dir_all(&path.dir) {
+                return Err(KvStoreError::InvalidPath {
+                    path: path.dir,
+                    source,
+                });
+            }
+            let lock = lock_dir(&path)?;
+            finish_clear(&path)?;
+            Some(Arc::new(lock))
+        };
+
+        let gens = sorted_gens(&path)?;
+        if read_only && gens.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no log files in {}", path.dir.display()),
+            )
+            .into());
         }
-    }
+        let Replayed {
+            index,
+            readers,
+            report,
+            expiring,
+            dirt,
+        } = replay(&path, &gens, &options, !read_only)?;
 
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        if let Some(_) = self.index.remove(&key) {
-            let command = Command::Rm { key };
-            serde_json::to_writer(&mut self.writer, &command)?;
-            self.writer.flush()?;
-            Ok(())
+        let writer = if read_only {
+            None
         } else {
-            return Err(KvStoreError::KeyNotFound);
+            let gen = gens.last().copied().unwrap_or(1);
+            Some(Arc::new(Mutex::new(KvStoreWriter {
+                gen,
+                writer: new_log_file(&path, gen, options.buffer_capacity)?,
+                dirt,
+                log_size: log_size(&path)?,
+                expiring,
+            })))
+        };
+        let path = Arc::new(path);
+        let bloom_filter = (options.bloom_filter_keys > 0).then(|| {
+            Arc::new(RwLock::new(build_bloom_filter(
+                &index,
+                options.bloom_filter_keys,
+            )))
+        });
+
+        let mut store = KvStore {
+            path: path.clone(),
+            lock,
+            writer,
+            reader: Mutex::new(KvStoreReader {
+                path,
+                safe_point: Arc::new(AtomicU64::new(0)),
+                readers,
+                codec: options.codec.clone(),
+                buffer_capacity: options.buffer_capacity,
+            }),
+            index: Arc::new(RwLock::new(index)),
+            value_cache: (options.value_cache_capacity > 0)
+                .then(|| Arc::new(Mutex::new(ValueCache::new(options.value_cache_capacity)))),
+            bloom_filter,
+            options,
+            metrics: Arc::new(MetricsCounters::default()),
+            watchers: Watchers::default(),
+            sweeper: None,
+            db: 0,
+        };
+        if !read_only && store.options.compact_on_open {
+            store.compact()?;
+        }
+        if let (false, Some(interval)) = (read_only, store.options.expiry_sweep_interval) {
+            store.sweeper = Some(Arc::new(Sweeper::spawn(store.clone(), interval)));
         }
+        Ok((store, report))
     }
 
-    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
-        let mut path: PathBuf = path.into();
-        if let Some(_path) = path.to_str() {
-            if _path.is_empty() {
-                path = current_dir()?;
+    /// Flushes the log and drops this handle, returning any error the flush hits. Other
+    /// clones stay usable.
+    pub fn close(self) -> Result<()> {
+        if let Some(writer) = &self.writer {
+            self.flush(&mut writer.lock().unwrap())?;
+        }
+        Ok(())
+    }
+
+    /// Re-reads every record of the log and checks that each index entry points at a
+    /// readable `Set` of its key, collecting what doesn't check out into the report.
+    ///
+    /// Reads go through fresh file handles, so damage done to the files since they were
+    /// loaded shows up too. Writes wait until the check is done.
+    pub fn verify(&self) -> Result<VerifyReport> {
+        let _writer = match &self.writer {
+            Some(writer) => {
+                let mut writer = writer.lock().unwrap();
+                self.flush(&mut writer)?;
+                Some(writer)
+            }
+            None => None,
+        };
+        let index = self.index.read().unwrap();
+        let mut reader = self.reader.lock().unwrap().clone();
+        let codec = &*self.options.codec;
+        let mut report = VerifyReport::default();
+
+        for gen in sorted_gens(&self.path)? {
+            let reader = reader.reader(gen)?;
+            let mut start = reader.seek(SeekFrom::Start(0))?;
+            let mut record = Vec::new();
+            while codec.read_record(reader, &mut record)? {
+                if codec.decode(&record, start).is_err() {
+                    report.corrupt_records.push((gen, start));
+                }
+                report.records += 1;
+                start += record.len() as u64;
             }
         }
 
-        if path.is_dir() {
-            path.push("default_log_file.txt");
+        for (key, cmd_position) in index.iter() {
+            let matches = match reader.read_command(cmd_position) {
+                Ok(Command::Set { key: found, .. })
+                | Ok(Command::SetBytes { key: found, .. })
+                | Ok(Command::SetCompressed { key: found, .. })
+                | Ok(Command::SetWithTtl { key: found, .. }) => &found == key,
+                _ => false,
+            };
+            if !matches {
+                report.mismatched_keys.push(key.clone());
+            }
         }
 
-        let mut writer = BufWriterWithPos::new(
-            OpenOptions::new()
-                .create(true)
-                .append(true)
-                .open(path.clone())?,
-        );
-
-        let mut index = BTreeMap::new();
-
-        let mut reader = BufReaderWithPos::new(File::open(path.clone())?);
-        let reader_clone = reader.source.get_mut();
-        let mut initial_pos = reader_clone.seek(SeekFrom::Start(0))?;
-        let mut stream = Deserializer::from_reader(reader_clone).into_iter::<Command>();
-        while let Some(cmd) = stream.next() {
-            let offset = stream.byte_offset() as u64;
-            match cmd? {
-                Command::Set { key, value: _ } => {
-                    index.insert(
-                        key,
-                        CommandPosition {
-                            start: initial_pos,
-                            length: offset - initial_pos,
-                        },
-                    );
+        Ok(report)
+    }
+
+    /// Rebuilds the index by replaying the log the way `open` does, for when the two
+    /// have diverged, e.g. after the files were edited by hand.
+    ///
+    /// The log files are reopened, but this clone's other state is kept. Other clones
+    /// share the new index but keep reading through the handles they already have.
+    pub fn reindex(&self) -> Result<()> {
+        let mut writer = match &self.writer {
+            Some(writer) => {
+                let mut writer = writer.lock().unwrap();
+                self.flush(&mut writer)?;
+                Some(writer)
+            }
+            None => None,
+        };
+        let mut index = self.index.write().unwrap();
+        let mut reader = self.reader.lock().unwrap();
+
+        let gens = sorted_gens(&self.path)?;
+        let replayed = replay(&self.path, &gens, &self.options, false)?;
+        *index = replayed.index;
+        reader.readers = replayed.readers;
+        self.forget_all_cached();
+        self.rebuild_bloom_filter(&index);
+
+        if let Some(writer) = &mut writer {
+            writer.writer = new_log_file(&self.path, writer.gen, self.options.buffer_capacity)?;
+            writer.dirt = replayed.dirt;
+            writer.log_size = log_size(&self.path)?;
+            writer.expiring = replayed.expiring;
+        }
+        Ok(())
+    }
+
+    /// Removes every key of the selected database. If no other database has any keys,
+    /// this deletes every generation and moves writes to a fresh one; otherwise it writes
+    /// an `Rm` per key.
+    ///
+    /// A fresh generation, rather than the truncated active one, keeps readers from
+    /// serving stale bytes they have buffered. A marker written before the old generations
+    /// are deleted keeps them from being replayed if a crash cuts the deleting short.
+    pub fn clear(&self) -> Result<()> {
+        let mut writer = self.lock_writer()?;
+        let mut index = self.index.write().unwrap();
+        if index.keys().any(|key| user_key(self.db, key).is_none()) {
+            let keys = index
+                .keys()
+                .filter(|key| user_key(self.db, key).is_some())
+                .cloned()
+                .collect();
+            return self.remove_keys(writer, index, keys).map(|_| ());
+        }
+        let reader = self.reader.lock().unwrap();
+
+        writer.writer.flush()?;
+        writer.gen += 1;
+        writer.writer = new_log_file(&self.path, writer.gen, self.options.buffer_capacity)?;
+        reader.safe_point.store(writer.gen, Ordering::SeqCst);
+        File::create(cleared_path(&self.path, writer.gen))?;
+        finish_clear(&self.path)?;
+
+        writer.dirt = 0;
+        writer.log_size = 0;
+        writer.expiring.clear();
+        for key in index.keys() {
+            self.watchers.notify(key, || None);
+        }
+        index.clear();
+        self.forget_all_cached();
+        self.rebuild_bloom_filter(&index);
+
+        Ok(())
+    }
+
+    /// Locks the writer, or fails with `KvStoreError::ReadOnly` if the store has none.
+    fn lock_writer(&self) -> Result<MutexGuard<'_, KvStoreWriter>> {
+        match &self.writer {
+            Some(writer) => Ok(writer.lock().unwrap()),
+            None => Err(KvStoreError::ReadOnly),
+        }
+    }
+
+    /// Reads the value of `key` for a caller holding the writer lock. Unlike `get`, this
+    /// leaves an expired key in the index, since forgetting it needs the writer lock.
+    fn current_value(&self, key: &str) -> Result<Option<String>> {
+        match self.index.read().unwrap().get(key) {
+            Some(cmd_position) => self.reader.lock().unwrap().read_value(cmd_position),
+            None => Ok(None),
+        }
+    }
+
+    /// Fails if `key` or a value of `value_len` bytes is over the configured limits. A
+    /// stored key is measured without its database prefix.
+    fn check_size(&self, key: &str, value_len: usize) -> Result<()> {
+        let key = user_key(self.db, key).unwrap_or(key);
+        if key.len() > self.options.max_key_bytes {
+            return Err(KvStoreError::KeyTooLarge {
+                size: key.len(),
+                max: self.options.max_key_bytes,
+            });
+        }
+        if value_len > self.options.max_value_bytes {
+            return Err(KvStoreError::ValueTooLarge {
+                size: value_len,
+                max: self.options.max_value_bytes,
+            });
+        }
+        Ok(())
+    }
+
+    /// Builds the command storing a string value, compressed if `compress_values` is set.
+    fn set_command(&self, key: String, value: String) -> Result<Command> {
+        self.check_size(&key, value.len())?;
+        if self.options.compress_values {
+            Ok(Command::SetCompressed {
+                key,
+                value: compress_value(value.into_bytes())?,
+            })
+        } else {
+            Ok(Command::Set { key, value })
+        }
+    }
+
+    /// Appends a command setting a key to the active generation and points the index at
+    /// it, returning where it landed.
+    fn append_set(&self, writer: &mut KvStoreWriter, command: Command) -> Result<CommandPosition> {
+        let key = match &command {
+            Command::Set { key, .. }
+            | Command::SetBytes { key, .. }
+            | Command::SetCompressed { key, .. }
+            | Command::SetWithTtl { key, .. } => key.clone(),
+            _ => return Err(KvStoreError::InvalidLogFileCommand),
+        };
+
+        let cmd_position = self.write_command(writer, &command)?;
+        self.flush(writer)?;
+        if let Command::SetWithTtl {
+            expires_at_unix_ms, ..
+        } = command
+        {
+            writer.expiring.insert(key.clone(), expires_at_unix_ms);
+        }
+        // After the index, so a watcher reading the key sees the new value
+        self.index_set(writer, key.clone(), cmd_position);
+        self.notify_set(&key, command);
+        MetricsCounters::add(&self.metrics.sets, 1);
+        Ok(cmd_position)
+    }
+
+    /// Serializes `command` to the active generation without flushing it, first rolling
+    /// over to a new generation if the active one has reached `max_segment_bytes`.
+    fn write_command(
+        &self,
+        writer: &mut KvStoreWriter,
+        command: &Command,
+    ) -> Result<CommandPosition> {
+        if writer.writer.position() > 0
+            && writer.writer.position() >= self.options.max_segment_bytes
+        {
+            // Synced regardless of `sync_on_write`, so `flush_durable` only ever has the
+            // active generation left to sync
+            writer.writer.flush()?;
+            writer.writer.source.get_ref().sync_all()?;
+            writer.gen += 1;
+            writer.writer = new_log_file(&self.path, writer.gen, self.options.buffer_capacity)?;
+        }
+
+        let start = writer.writer.position();
+        writer
+            .writer
+            .write_all(&self.options.codec.encode(command)?)?;
+        let length = writer.writer.position() - start;
+        writer.log_size += length;
+        Ok(CommandPosition {
+            gen: writer.gen,
+            start,
+            length,
+        })
+    }
+
+    /// Tells the watchers of `key` about the value `command` has just stored.
+    fn notify_set(&self, key: &str, command: Command) {
+        self.watchers
+            .notify(key, || command_value(command).ok().flatten());
+    }
+
+    /// Points the index at a flushed `Set` command, counting the replaced one as dirt.
+    fn index_set(&self, writer: &mut KvStoreWriter, key: String, cmd_position: CommandPosition) {
+        let mut index = self.index.write().unwrap();
+        self.forget_cached(&key);
+        if let Some(filter) = &self.bloom_filter {
+            filter.write().unwrap().insert(&key);
+        }
+        if let Some(old_value) = index.insert(key, cmd_position) {
+            writer.dirt += old_value.length;
+        }
+    }
+
+    /// Drops the cached value of `key`, if any. Called with the index locked for writing, or
+    /// after it changed, so no read can cache the old value afterwards.
+    fn forget_cached(&self, key: &str) {
+        if let Some(cache) = &self.value_cache {
+            cache.lock().unwrap().remove(key);
+        }
+    }
+
+    fn forget_all_cached(&self) {
+        if let Some(cache) = &self.value_cache {
+            cache.lock().unwrap().clear();
+        }
+    }
+
+    /// Returns whether the bloom filter rules `key` out, meaning it is absent from the
+    /// index. Always `false` without a filter.
+    fn definitely_absent(&self, key: &str) -> bool {
+        self.bloom_filter
+            .as_ref()
+            .is_some_and(|filter| !filter.read().unwrap().may_contain(key))
+    }
+
+    /// Like `definitely_absent`, counting a skipped key as a missed get.
+    fn skip_absent(&self, key: &str) -> bool {
+        let absent = self.definitely_absent(key);
+        if absent {
+            self.metrics.record_get(false);
+            MetricsCounters::add(&self.metrics.bloom_filter_skips, 1);
+        }
+        absent
+    }
+
+    /// Replaces the bloom filter with one holding exactly the keys of `index`, sized for
+    /// at least as many keys as it has. Called with the index locked.
+    fn rebuild_bloom_filter(&self, index: &BTreeMap<String, CommandPosition>) {
+        if let Some(filter) = &self.bloom_filter {
+            *filter.write().unwrap() = build_bloom_filter(index, self.options.bloom_filter_keys);
+        }
+    }
+
+    /// Removes every key whose TTL has run out, writing an `Rm` for each, and returns how
+    /// many there were.
+    ///
+    /// Reads already treat such keys as absent, but only forget them once read. Finding
+    /// them means reading the records of the keys whose TTL has come due, during which
+    /// writes wait.
+    pub fn remove_expired(&self) -> Result<usize> {
+        let mut writer = self.lock_writer()?;
+        let now = now_unix_ms();
+        let due: Vec<String> = writer
+            .expiring
+            .iter()
+            .filter(|(_, &expires_at_unix_ms)| expires_at_unix_ms <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        let mut expired = Vec::new();
+        {
+            let index = self.index.read().unwrap();
+            let mut reader = self.reader.lock().unwrap();
+            for key in due {
+                let command = match index.get(&key) {
+                    Some(cmd_position) => Some(reader.read_command(cmd_position)?),
+                    None => None,
+                };
+                match command {
+                    Some(Command::SetWithTtl {
+                        expires_at_unix_ms, ..
+                    }) if expires_at_unix_ms > now => {
+                        writer.expiring.insert(key, expires_at_unix_ms);
+                    }
+                    // Keeps its entry until it is gone from the index, in case removing
+                    // it fails
+                    Some(Command::SetWithTtl { .. }) => expired.push(key),
+                    _ => {
+                        writer.expiring.remove(&key);
+                    }
                 }
-                Command::Rm { key } => {
-                    index.remove(&key);
+            }
+        }
+        if expired.is_empty() {
+            return Ok(0);
+        }
+
+        let index = self.index.write().unwrap();
+        self.remove_keys(writer, index, expired)
+    }
+
+    /// Writes an `Rm` for each of the stored `keys` with a single flush, then drops them
+    /// from the index, returning how many there were.
+    fn remove_keys(
+        &self,
+        mut writer: MutexGuard<KvStoreWriter>,
+        mut index: RwLockWriteGuard<BTreeMap<String, CommandPosition>>,
+        keys: Vec<String>,
+    ) -> Result<usize> {
+        for key in &keys {
+            let rm_position = self.write_command(&mut writer, &Command::Rm { key: key.clone() })?;
+            writer.dirt += rm_position.length;
+        }
+        self.flush(&mut writer)?;
+        for key in &keys {
+            if let Some(cmd_position) = index.remove(key) {
+                writer.dirt += cmd_position.length;
+            }
+            self.forget_cached(key);
+            self.watchers.notify(key, || None);
+        }
+        drop(index);
+        MetricsCounters::add(&self.metrics.removes, keys.len());
+
+        self.compact_if_needed(writer)?;
+        Ok(keys.len())
+    }
+
+    /// Drops `key` from the index after a read found it expired, unless it has been set
+    /// again since, and tells its watchers. Its record becomes dirt.
+    fn forget_expired(&self, key: &str, gen: u64, start: u64) {
+        let mut writer = self.writer.as_ref().map(|writer| writer.lock().unwrap());
+        let mut index = self.index.write().unwrap();
+        if let Some(cmd_position) = index.get(key) {
+            if cmd_position.gen == gen && cmd_position.start == start {
+                if let Some(writer) = &mut writer {
+                    writer.dirt += cmd_position.length;
                 }
-                _ => {}
+                index.remove(key);
+                self.watchers.notify(key, || None);
             }
-            initial_pos = offset;
         }
-        writer.position = initial_pos;
+    }
 
-        let reader = BufReaderWithPos::new(File::open(path.clone())?);
+    /// Flushes the active generation, syncing it to disk if `sync_on_write` is set.
+    fn flush(&self, writer: &mut KvStoreWriter) -> Result<()> {
+        writer.writer.flush()?;
+        if self.options.sync_on_write {
+            writer.writer.source.get_ref().sync_all()?;
+        }
+        Ok(())
+    }
 
-        Ok(KvStore {
-            path,
-            reader,
-            writer,
-            index,
-            dirt: 0,
+    /// Releases the writer and compacts if the compaction strategy says enough stale bytes
+    /// have piled up.
+    fn compact_if_needed(&self, writer: MutexGuard<KvStoreWriter>) -> Result<()> {
+        let needs_compaction = match self.options.compaction_strategy {
+            Some(strategy) => strategy.should_compact(writer.dirt, writer.log_size),
+            None => {
+                CompactionStrategy::OnThreshold {
+                    bytes: self.options.compaction_threshold,
+                }
+                .should_compact(writer.dirt, writer.log_size)
+                    || CompactionStrategy::OnRatio {
+                        ratio: self.options.compaction_ratio,
+                    }
+                    .should_compact(writer.dirt, writer.log_size)
+            }
+        };
+        drop(writer);
+
+        if needs_compaction {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Estimates what `compact` would reclaim right now, from the index and the size of
+    /// the log files, without rewriting anything.
+    pub fn compaction_estimate(&self) -> Result<CompactionEstimate> {
+        let _writer = self.writer.as_ref().map(|writer| writer.lock().unwrap());
+        let live_bytes = self
+            .index
+            .read()
+            .unwrap()
+            .values()
+            .map(|cmd_position| cmd_position.length)
+            .sum();
+        let current_bytes = log_size(&self.path)?;
+        Ok(CompactionEstimate {
+            current_bytes,
+            live_bytes,
+            reclaimable_bytes: current_bytes.saturating_sub(live_bytes),
         })
     }
 
-    fn compact(&mut self) -> Result<()> {
-        let mut curr_position = 0;
-        let mut new_values = vec![];
+    /// Rewrites the live `Set` commands into a fresh generation and deletes the older
+    /// log files. New writes go to the generation after the compacted one.
+    ///
+    /// Returns the number of bytes reclaimed, i.e. the old log size minus the new one.
+    /// Under `CompactionStrategy::Never` this does nothing and returns 0.
+    pub fn compact(&self) -> Result<u64> {
+        let mut writer = self.lock_writer()?;
+        if self.options.compaction_strategy == Some(CompactionStrategy::Never) {
+            return Ok(0);
+        }
+        let mut index = self.index.write().unwrap();
+        let mut reader = self.reader.lock().unwrap();
+        let old_size = log_size(&self.path)?;
 
-        for cmds in self.index.values_mut() {
-            if self.reader.position != cmds.start {
-                self.reader.seek(SeekFrom::Start(cmds.start))?;
+        let compaction_gen = writer.gen + 1;
+        let temp_path = compaction_temp_path(&self.path, compaction_gen);
+        let rewritten = self
+            .write_compacted(&index, &mut reader, compaction_gen, &temp_path)
+            .and_then(|positions| {
+                let new_writer =
+                    new_log_file(&self.path, compaction_gen + 1, self.options.buffer_capacity)?;
+                fs::rename(&temp_path, log_path(&self.path, compaction_gen))?;
+                Ok((positions, new_writer))
+            });
+        let (positions, new_writer) = match rewritten {
+            Ok(rewritten) => rewritten,
+            Err(err) => {
+                let _ = fs::remove_file(&temp_path);
+                return Err(err);
             }
-            let reader = self.reader.source.get_ref();
-            let taken = reader.take(cmds.length);
+        };
 
-            if let Command::Set { value, key } = serde_json::from_reader(taken)? {
-                cmds.start = curr_position;
-                curr_position += cmds.length;
-                new_values.push(Command::Set {
-                    key: key.clone(),
-                    value,
-                });
+        // Only now that the compacted generation is complete on disk does anything point
+        // at it
+        writer.log_size = positions
+            .last()
+            .map_or(0, |position| position.start + position.length);
+        for (cmd_position, position) in index.values_mut().zip(positions) {
+            *cmd_position = position;
+        }
+        writer.gen = compaction_gen + 1;
+        writer.writer = new_writer;
+        writer.dirt = 0;
+        self.rebuild_bloom_filter(&index);
+
+        reader.safe_point.store(compaction_gen, Ordering::SeqCst);
+        for gen in sorted_gens(&self.path)? {
+            if gen < compaction_gen {
+                self.retire_generation(gen)?;
             }
         }
 
-        fs::remove_file(&self.path)?;
-        self.writer = BufWriterWithPos::new(
-            OpenOptions::new()
-                .create(true)
-                .append(true)
-                .open(&self.path)?,
-        );
+        MetricsCounters::add(&self.metrics.compactions, 1);
+        Ok(old_size.saturating_sub(log_size(&self.path)?))
+    }
 
-        self.reader = BufReaderWithPos::new(File::open(&self.path)?);
-        for cmd in new_values {
-            serde_json::to_writer(&mut self.writer, &cmd)?;
-        }
-        self.writer.flush()?;
-        self.reader.seek(SeekFrom::Start(0))?;
-        self.writer.seek(SeekFrom::Start(0))?;
+    /// Returns the generations of the log files the store reads, in ascending order.
+    pub fn list_generations(&self) -> Result<Vec<u64>> {
+        sorted_gens(&self.path)
+    }
+
+    /// Returns the superseded generations kept by `keep_generations`, in ascending order.
+    pub fn retained_generations(&self) -> Result<Vec<u64>> {
+        retained_gens(&self.path)
+    }
 
+    /// Deletes a generation superseded by compaction, or keeps it aside if
+    /// `keep_generations` allows, deleting the oldest kept one instead.
+    fn retire_generation(&self, gen: u64) -> Result<()> {
+        if self.options.keep_generations == 0 {
+            fs::remove_file(log_path(&self.path, gen))?;
+            return Ok(());
+        }
+        fs::rename(log_path(&self.path, gen), retained_path(&self.path, gen))?;
+        let retained = retained_gens(&self.path)?;
+        let excess = retained.len().saturating_sub(self.options.keep_generations);
+        for &gen in &retained[..excess] {
+            fs::remove_file(retained_path(&self.path, gen))?;
+        }
         Ok(())
     }
+
+    /// Writes a compacted copy of the store to `dest_dir` as a single generation synced to
+    /// disk, without touching the store itself. The copy opens with the same options as
+    /// the store, or with `KvStore::open` if those are the defaults.
+    ///
+    /// `dest_dir` is created if needed, and must not already hold this store's log files.
+    pub fn snapshot(&self, dest_dir: impl Into<PathBuf>) -> Result<()> {
+        let dest = StorePath {
+            dir: dest_dir.into(),
+            name: self.path.name.clone(),
+        };
+        if let Err(source) = fs::create_dir_all(&dest.dir) {
+            return Err(KvStoreError::InvalidPath {
+                path: dest.dir,
+                source,
+            });
+        }
+        if !sorted_gens(&dest)?.is_empty() {
+            return Err(KvStoreError::InvalidPath {
+                path: dest.dir,
+                source: io::ErrorKind::AlreadyExists.into(),
+            });
+        }
+
+        let index = self.index.read().unwrap();
+        let mut reader = self.reader.lock().unwrap();
+        let temp_path = compaction_temp_path(&dest, 1);
+        let written = self
+            .write_compacted(&index, &mut reader, 1, &temp_path)
+            .and_then(|_| Ok(fs::rename(&temp_path, log_path(&dest, 1))?));
+        if written.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+        written
+    }
+
+    /// Copies every live record, in index order, into a new file at `temp_path` synced to
+    /// disk, and returns where each will be once the file is renamed to generation `gen`.
+    fn write_compacted(
+        &self,
+        index: &BTreeMap<String, CommandPosition>,
+        reader: &mut KvStoreReader,
+        gen: u64,
+        temp_path: &Path,
+    ) -> Result<Vec<CommandPosition>> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(temp_path)?;
+        let mut compaction_writer =
+            BufWriterWithPos::with_capacity(self.options.buffer_capacity, file);
+
+        let mut positions = Vec::with_capacity(index.len());
+        for cmd_position in index.values() {
+            let reader = reader.reader(cmd_position.gen)?;
+            reader.seek(SeekFrom::Start(cmd_position.start))?;
+            let mut taken = reader.take(cmd_position.length);
+
+            let start = compaction_writer.position();
+            io::copy(&mut taken, &mut compaction_writer)?;
+            positions.push(CommandPosition {
+                gen,
+                start,
+                length: compaction_writer.position() - start,
+            });
+        }
+        compaction_writer.flush()?;
+        compaction_writer.source.get_ref().sync_all()?;
+        Ok(positions)
+    }
+}
+
+/// Returns the value a command setting a key stores, as a string, or `None` if it has
+/// expired.
+fn command_value(command: Command) -> Result<Option<String>> {
+    match command {
+        Command::Set { value, .. } => Ok(Some(value)),
+        Command::SetBytes { value, .. } => Ok(Some(String::from_utf8(value)?)),
+        Command::SetCompressed { value, .. } => {
+            Ok(Some(String::from_utf8(decompress_value(&value)?)?))
+        }
+        Command::SetWithTtl {
+            expires_at_unix_ms, ..
+        } if expires_at_unix_ms <= now_unix_ms() => Ok(None),
+        Command::SetWithTtl { value, .. } => Ok(Some(value)),
+        _ => Err(KvStoreError::InvalidLogFileCommand),
+    }
+}
+
+/// Prefixes `value` with `VALUE_GZIP` and gzips it, or with `VALUE_RAW` if it is too
+/// short for that to pay off or doesn't shrink.
+fn compress_value(value: Vec<u8>) -> Result<Vec<u8>> {
+    if value.len() >= COMPRESSION_MIN_BYTES {
+        let mut encoder = GzEncoder::new(vec![VALUE_GZIP], Compression::default());
+        encoder.write_all(&value)?;
+        let compressed = encoder.finish()?;
+        if compressed.len() < value.len() + 1 {
+            return Ok(compressed);
+        }
+    }
+
+    let mut raw = Vec::with_capacity(value.len() + 1);
+    raw.push(VALUE_RAW);
+    raw.extend_from_slice(&value);
+    Ok(raw)
+}
+
+/// Reverses `compress_value`.
+fn decompress_value(value: &[u8]) -> Result<Vec<u8>> {
+    match value.split_first() {
+        Some((&VALUE_RAW, raw)) => Ok(raw.to_vec()),
+        Some((&VALUE_GZIP, compressed)) => {
+            let mut decompressed = Vec::new();
+            GzDecoder::new(compressed).read_to_end(&mut decompressed)?;
+            Ok(decompressed)
+        }
+        _ => Err(KvStoreError::InvalidLogFileCommand),
+    }
+}
+
+/// Adds `delta` to a value stored as an integer by `incr`, treating an absent value as 0.
+pub(crate) fn add_to_integer(value: Option<String>, delta: i64) -> Result<i64> {
+    let current: i64 = match value {
+        Some(value) => value
+            .parse()
+            .map_err(|_| KvStoreError::NotAnInteger(value))?,
+        None => 0,
+    };
+    current
+        .checked_add(delta)
+        .ok_or(KvStoreError::IntegerOverflow)
+}
+
+fn now_unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// Whether `range` cannot contain any key. `BTreeMap::range` panics on such ranges.
+pub(crate) fn is_empty_range(range: &impl RangeBounds<String>) -> bool {
+    match (range.start_bound(), range.end_bound()) {
+        (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        (
+            Bound::Included(start) | Bound::Excluded(start),
+            Bound::Included(end) | Bound::Excluded(end),
+        ) => start > end,
+        _ => false,
+    }
+}
+
+/// Where a store keeps its files: a directory, and the name that sets the store's files
+/// apart from other stores' in it. The default store's name is empty.
+#[derive(Debug)]
+struct StorePath {
+    dir: PathBuf,
+    name: String,
+}
+
+/// Takes the exclusive lock on the store's lock file, creating the file if needed.
+fn lock_dir(path: &StorePath) -> Result<File> {
+    let file_name = if path.name.is_empty() {
+        LOCK_FILE.to_owned()
+    } else {
+        format!("{}.{}", path.name, LOCK_FILE)
+    };
+    let lock = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path.dir.join(file_name))?;
+    match lock.try_lock_exclusive() {
+        Ok(()) => Ok(lock),
+        Err(err) if err.kind() == fs2::lock_contended_error().kind() => {
+            Err(KvStoreError::AlreadyLocked)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn log_path(path: &StorePath, gen: u64) -> PathBuf {
+    if path.name.is_empty() {
+        path.dir.join(format!("{}.log", gen))
+    } else {
+        path.dir.join(format!("{}.{}.log", path.name, gen))
+    }
+}
+
+/// Where `compact` writes generation `gen` before renaming it into place. Not a `.log`
+/// file, so a compaction cut short is never replayed.
+fn compaction_temp_path(path: &StorePath, gen: u64) -> PathBuf {
+    log_path(path, gen).with_extension("log.compacting")
+}
+
+/// Where a generation superseded by compaction is kept under `keep_generations`. Not a
+/// `.log` file, so it is never replayed.
+fn retained_path(path: &StorePath, gen: u64) -> PathBuf {
+    log_path(path, gen).with_extension("log.old")
+}
+
+/// Marks the generations below `gen` as cleared until `clear` has deleted them. Not a
+/// `.log` file, so it is never replayed.
+fn cleared_path(path: &StorePath, gen: u64) -> PathBuf {
+    log_path(path, gen).with_extension("log.cleared")
+}
+
+/// Deletes the generations below the latest `clear` marker, then the markers.
+fn finish_clear(path: &StorePath) -> Result<()> {
+    let cleared = gens_with_suffix(path, ".log.cleared")?;
+    if let Some(&last) = cleared.last() {
+        for gen in gens_with_suffix(path, ".log")? {
+            if gen < last {
+                fs::remove_file(log_path(path, gen))?;
+            }
+        }
+    }
+    for gen in cleared {
+        fs::remove_file(cleared_path(path, gen))?;
+    }
+    Ok(())
+}
+
+/// Returns a bloom filter holding the keys of `index`, sized for `expected_keys` or the
+/// number of keys, whichever is larger.
+fn build_bloom_filter(
+    index: &BTreeMap<String, CommandPosition>,
+    expected_keys: usize,
+) -> BloomFilter {
+    let mut filter = BloomFilter::new(expected_keys.max(index.len()));
+    for key in index.keys() {
+        filter.insert(key);
+    }
+    filter
+}
+
+/// Returns the generations of the store's log files, in ascending order.
+fn sorted_gens(path: &StorePath) -> Result<Vec<u64>> {
+    let mut gens = gens_with_suffix(path, ".log")?;
+    // Left by a `clear` that hasn't finished deleting them
+    if let Some(&cleared) = gens_with_suffix(path, ".log.cleared")?.last() {
+        gens.retain(|&gen| gen >= cleared);
+    }
+    Ok(gens)
+}
+
+/// Returns the generations kept aside by `keep_generations`, in ascending order.
+fn retained_gens(path: &StorePath) -> Result<Vec<u64>> {
+    gens_with_suffix(path, ".log.old")
+}
+
+fn gens_with_suffix(path: &StorePath, suffix: &str) -> Result<Vec<u64>> {
+    let mut gens: Vec<u64> = fs::read_dir(&path.dir)?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<io::Result<Vec<PathBuf>>>()?
+        .iter()
+        .filter(|file| file.is_file())
+        .filter_map(|file| {
+            let stem = file.file_name()?.to_str()?.strip_suffix(suffix)?;
+            let gen = if path.name.is_empty() {
+                stem
+            } else {
+                stem.strip_prefix(path.name.as_str())?.strip_prefix('.')?
+            };
+            gen.parse::<u64>().ok()
+        })
+        .collect();
+    gens.sort_unstable();
+    Ok(gens)
+}
+
+fn log_size(path: &StorePath) -> Result<u64> {
+    let mut size = 0;
+    for gen in sorted_gens(path)? {
+        size += fs::metadata(log_path(path, gen))?.len();
+    }
+    Ok(size)
+}
+
+/// Opens (or creates) the log file for `gen` for appending, buffering `capacity` bytes.
+fn new_log_file(path: &StorePath, gen: u64, capacity: usize) -> Result<BufWriterWithPos<File>> {
+    let path = log_path(path, gen);
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let mut writer = BufWriterWithPos::with_capacity(capacity, file);
+    writer.seek(SeekFrom::End(0))?;
+    Ok(writer)
 }
 
+/// An index rebuilt from the log, with the readers left open on each generation.
+struct Replayed {
+    index: BTreeMap<String, CommandPosition>,
+    readers: BTreeMap<u64, BufReaderWithPos<File>>,
+    report: OpenReport,
+    /// When each live key set with a TTL expires.
+    expiring: BTreeMap<String, u64>,
+    /// The bytes of the replayed log that no live key points at.
+    dirt: u64,
+}
+
+/// Replays every generation in `gens` into a new index.
+///
+/// With `truncate_tail`, a record left incomplete by a crash is cut off so new writes
+/// don't follow it.
+fn replay(
+    path: &StorePath,
+    gens: &[u64],
+    options: &KvStoreOptions,
+    truncate_tail: bool,
+) -> Result<Replayed> {
+    let mut index = BTreeMap::new();
+    let mut readers = BTreeMap::new();
+    let mut report = OpenReport::default();
+    let mut expiring = BTreeMap::new();
+    let mut size = 0;
+    for &gen in gens {
+        let file_path = log_path(path, gen);
+        let mut reader =
+            BufReaderWithPos::with_capacity(options.buffer_capacity, File::open(&file_path)?);
+        let valid = load(
+            gen,
+            &*options.codec,
+            &mut reader,
+            &mut index,
+            &mut report,
+            &mut expiring,
+        )?;
+        size += valid;
+        // The reader has buffered the dropped bytes, so it is reopened on first use.
+        if truncate_tail && valid < fs::metadata(&file_path)?.len() {
+            OpenOptions::new()
+                .write(true)
+                .open(&file_path)?
+                .set_len(valid)?;
+            continue;
+        }
+        readers.insert(gen, reader);
+    }
+    expiring.retain(|key, _| index.contains_key(key));
+    let live: u64 = index.values().map(|cmd_position| cmd_position.length).sum();
+    Ok(Replayed {
+        index,
+        readers,
+        report,
+        expiring,
+        dirt: size - live,
+    })
+}
+
+/// Replays the log of `gen` into `index`, returning the length of its complete records.
+/// Oddities that don't stop the replay are counted in `report`.
+///
+/// A truncated final record is left out; a damaged one anywhere else is an error.
+fn load(
+    gen: u64,
+    codec: &dyn LogCodec,
+    reader: &mut BufReaderWithPos<File>,
+    index: &mut BTreeMap<String, CommandPosition>,
+    report: &mut OpenReport,
+    expiring: &mut BTreeMap<String, u64>,
+) -> Result<u64> {
+    let mut initial_pos = reader.seek(SeekFrom::Start(0))?;
+    let mut record = Vec::new();
+    while codec.read_record(reader, &mut record)? {
+        let offset = initial_pos + record.len() as u64;
+        let cmd_position = CommandPosition {
+            gen,
+            start: initial_pos,
+            length: offset - initial_pos,
+        };
+        match codec.decode(&record, initial_pos)? {
+            Command::SetWithTtl {
+                key,
+                expires_at_unix_ms,
+                ..
+            } if expires_at_unix_ms <= now_unix_ms() => {
+                index.remove(&key);
+            }
+            Command::SetWithTtl {
+                key,
+                expires_at_unix_ms,
+                ..
+            } => {
+                expiring.insert(key.clone(), expires_at_unix_ms);
+                index.insert(key, cmd_position);
+            }
+            Command::Set { key, .. }
+            | Command::SetBytes { key, .. }
+            | Command::SetCompressed { key, .. } => {
+                expiring.remove(&key);
+                index.insert(key, cmd_position);
+            }
+            Command::Rm { key } if index.remove(&key).is_none() => {
+                report.dangling_removes += 1;
+            }
+            _ => {}
+        }
+        initial_pos = offset;
+    }
+    Ok(initial_pos)
+}
+
+/// A buffered writer that tracks its position in the underlying stream.
+///
+/// `position` is the offset the next byte written will land at: the underlying stream's
+/// offset once the buffer is flushed. Writes and seeks keep it up to date.
 #[derive(Debug)]
 pub struct BufWriterWithPos<T: Write + Seek> {
     source: BufWriter<T>,
-    pub position: u64,
+    position: u64,
 }
 
 impl<T: Write + Seek> BufWriterWithPos<T> {
+    /// Wraps `source`, which must be at offset 0. Seek to start elsewhere.
     pub fn new(source: T) -> Self {
         Self {
             source: BufWriter::new(source),
             position: 0,
         }
     }
+
+    /// Like `new`, but buffers up to `capacity` bytes before writing through.
+    pub fn with_capacity(capacity: usize, source: T) -> Self {
+        Self {
+            source: BufWriter::with_capacity(capacity, source),
+            position: 0,
+        }
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position
+    }
 }
 
 impl<T: Write + Seek> Write for BufWriterWithPos<T> {
@@ -221,6 +2103,14 @@ impl<T: Write + Seek> Seek for BufWriterWithPos<T> {
     }
 }
 
+/// A buffered reader that tracks its position in the underlying stream.
+///
+/// `position` is the offset of the next byte to be read: the underlying stream's offset
+/// less whatever is still buffered. Reads, `BufRead::consume` and seeks keep it up to
+/// date.
+///
+/// Seeking to an absolute position within the buffered bytes moves inside the buffer
+/// instead of discarding it, so nearby reads don't go back to the file.
 #[derive(Debug)]
 pub struct BufReaderWithPos<T: Read + Seek> {
     source: BufReader<T>,
@@ -228,12 +2118,25 @@ pub struct BufReaderWithPos<T: Read + Seek> {
 }
 
 impl<T: Read + Seek> BufReaderWithPos<T> {
+    /// Wraps `source`, which must be at offset 0. Seek to start elsewhere.
     pub fn new(source: T) -> Self {
         Self {
             source: BufReader::new(source),
             position: 0,
         }
     }
+
+    /// Like `new`, but reads ahead up to `capacity` bytes at a time.
+    pub fn with_capacity(capacity: usize, source: T) -> Self {
+        Self {
+            source: BufReader::with_capacity(capacity, source),
+            position: 0,
+        }
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position
+    }
 }
 
 impl<T: Read + Seek> Read for BufReaderWithPos<T> {
@@ -244,9 +2147,27 @@ impl<T: Read + Seek> Read for BufReaderWithPos<T> {
     }
 }
 
+impl<T: Read + Seek> BufRead for BufReaderWithPos<T> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.source.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.source.consume(amt);
+        self.position += amt as u64;
+    }
+}
+
 impl<T: Read + Seek> Seek for BufReaderWithPos<T> {
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
-        self.position = self.source.seek(pos)?;
+        self.position = match pos {
+            SeekFrom::Start(start) => {
+                self.source
+                    .seek_relative(start as i64 - self.position as i64)?;
+                start
+            }
+            pos => self.source.seek(pos)?,
+        };
         Ok(self.position)
     }
 }
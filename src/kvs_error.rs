@@ -1,4 +1,4 @@
-use std::io;
+use std::{io, path::PathBuf, string::FromUtf8Error};
 
 use thiserror::Error;
 
@@ -6,18 +6,79 @@ pub type Result<T> = std::result::Result<T, KvStoreError>;
 
 #[derive(Error, Debug)]
 pub enum KvStoreError {
-    #[error("Failed to read/write")]
+    #[error("IO error: {0}")]
     IoError(#[from] io::Error),
-    #[error("Failed to serialize")]
+    #[error("Serialization error: {0}")]
     SerdeSerError(#[from] serde_json::Error),
-    #[error("No path")]
-    No,
     #[error("Key not found")]
     KeyNotFound,
     #[error("Invalid log file command")]
     InvalidLogFileCommand,
-    #[error("Invalid file")]
-    InvalidFile,
-    #[error("Failed to encode/decode")]
+    #[error("Bincode error: {0}")]
     BincodeError(#[from] bincode::Error),
+    #[error("Sled operation failed")]
+    SledError(#[from] sled::Error),
+    #[error("Invalid UTF-8 value")]
+    Utf8Error(#[from] FromUtf8Error),
+    #[error("Can't use {} as a store directory: {source}", path.display())]
+    InvalidPath { path: PathBuf, source: io::Error },
+    #[error("Corrupt log record at offset {offset}")]
+    CorruptLog { offset: u64 },
+    #[error("Checksum mismatch for the log record at offset {offset}")]
+    ChecksumMismatch { offset: u64 },
+    #[error("Invalid address {0}")]
+    InvalidAddress(String),
+    #[error("Invalid engine {0}, expected kvs, sled or memory")]
+    InvalidEngine(String),
+    #[error("{0} is not a writable directory")]
+    InvalidDirectory(String),
+    #[error("Value {0} is not an integer")]
+    NotAnInteger(String),
+    #[error("Integer overflow")]
+    IntegerOverflow,
+    #[error("The store is already open in another process or handle")]
+    AlreadyLocked,
+    #[error("The store was opened read-only")]
+    ReadOnly,
+    #[error("Unauthorized")]
+    Unauthorized,
+    #[error("The engine has a single database")]
+    SingleDatabase,
+    #[error("Batches cannot be nested")]
+    NestedBatch,
+    #[error("Server error: {0}")]
+    ServerError(String),
+    #[error("Invalid config file: {0}")]
+    InvalidConfig(#[from] toml::de::Error),
+    #[error("Key of {size} bytes is over the limit of {max}")]
+    KeyTooLarge { size: usize, max: usize },
+    #[error("Value of {size} bytes is over the limit of {max}")]
+    ValueTooLarge { size: usize, max: usize },
+    #[error("Keys starting with a NUL character are reserved")]
+    ReservedKey,
+    #[error("Timed out waiting for the server")]
+    Timeout,
+    #[error("Engine mismatch: store was created with {persisted}, not {requested}")]
+    EngineMismatch {
+        persisted: String,
+        requested: String,
+    },
+}
+
+impl KvStoreError {
+    /// The code the binaries exit with on this error, so scripts can tell failures apart:
+    /// 1 for a missing key, 2 for an IO failure, 3 for a corrupt log and 4 for anything
+    /// else. Success is 0.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            KvStoreError::KeyNotFound => 1,
+            KvStoreError::IoError(_) | KvStoreError::InvalidPath { .. } | KvStoreError::Timeout => {
+                2
+            }
+            KvStoreError::CorruptLog { .. }
+            | KvStoreError::ChecksumMismatch { .. }
+            | KvStoreError::InvalidLogFileCommand => 3,
+            _ => 4,
+        }
+    }
 }
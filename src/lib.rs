@@ -1,10 +1,32 @@
+mod bloom;
 mod client_commands;
+mod codec;
+mod db;
 mod engine;
+mod frame;
 mod kvs;
 mod kvs_error;
+mod memory_engine;
 mod response;
 mod server_commands;
-pub use crate::kvs::KvStore;
-pub use client_commands::{ClientArgs, Command, CommandPosition, KvsClient};
+mod sled_engine;
+mod thread_pool;
+mod transport;
+mod value_cache;
+mod watch;
+pub use crate::kvs::{
+    BufReaderWithPos, BufWriterWithPos, CompactionEstimate, CompactionStrategy, KvStore,
+    KvStoreOptions, Metrics, OpenReport, VerifyReport,
+};
+pub use client_commands::{
+    ClientArgs, Command, CommandPosition, KvsClient, OutputFormat, ReconnectingClient,
+};
+pub use codec::{BincodeCodec, JsonCodec, LogCodec};
+pub use engine::{KvsEngine, Stats};
+pub use frame::{read_frame, try_read_frame, write_frame, MAX_FRAME_LEN};
 pub use kvs_error::{KvStoreError, Result};
-pub use server_commands::{KvsServer, ServerArgs};
+pub use memory_engine::MemoryKvsEngine;
+pub use response::{ErrorKind, Response};
+pub use server_commands::{KvsServer, ServerArgs, ServerConfig};
+pub use sled_engine::SledKvsEngine;
+pub use thread_pool::{SharedQueueThreadPool, ThreadPool};
@@ -0,0 +1,123 @@
+use crate::{
+    engine::{KvsEngine, Stats},
+    kvs::{add_to_integer, is_empty_range},
+    kvs_error::Result,
+    watch::Watchers,
+    KvStoreError,
+};
+use std::{
+    collections::HashMap,
+    ops::{Bound, RangeBounds},
+    sync::{mpsc::Receiver, Arc, RwLock},
+};
+
+/// A `KvsEngine` that keeps its pairs in memory only, for tests and caches.
+///
+/// Nothing touches the disk, so the data is gone once the last clone is dropped.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryKvsEngine {
+    map: Arc<RwLock<HashMap<String, String>>>,
+    watchers: Watchers,
+}
+
+impl MemoryKvsEngine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the pairs whose keys pass `filter`, sorted by key.
+    fn sorted_pairs(&self, filter: impl Fn(&String) -> bool) -> Vec<(String, String)> {
+        let mut pairs: Vec<(String, String)> = self
+            .map
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(key, _)| filter(key))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        pairs.sort_unstable();
+        pairs
+    }
+}
+
+impl KvsEngine for MemoryKvsEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let mut map = self.map.write().unwrap();
+        self.watchers.notify(&key, || Some(value.clone()));
+        map.insert(key, value);
+        Ok(())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self.map.read().unwrap().get(&key).cloned())
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.map
+            .write()
+            .unwrap()
+            .remove(&key)
+            .ok_or(KvStoreError::KeyNotFound)?;
+        self.watchers.notify(&key, || None);
+        Ok(())
+    }
+
+    fn compare_and_swap(&self, key: String, expected: Option<String>, new: String) -> Result<bool> {
+        let mut map = self.map.write().unwrap();
+        if map.get(&key) != expected.as_ref() {
+            return Ok(false);
+        }
+        self.watchers.notify(&key, || Some(new.clone()));
+        map.insert(key, new);
+        Ok(true)
+    }
+
+    fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        let mut map = self.map.write().unwrap();
+        let value = add_to_integer(map.get(&key).cloned(), delta)?;
+        self.watchers.notify(&key, || Some(value.to_string()));
+        map.insert(key, value.to_string());
+        Ok(value)
+    }
+
+    fn prefix_scan(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        Ok(self.sorted_pairs(|key| key.starts_with(prefix)))
+    }
+
+    fn remove_prefix(&self, prefix: &str) -> Result<usize> {
+        let mut map = self.map.write().unwrap();
+        let before = map.len();
+        map.retain(|key, _| {
+            let removed = key.starts_with(prefix);
+            if removed {
+                self.watchers.notify(key, || None);
+            }
+            !removed
+        });
+        Ok(before - map.len())
+    }
+
+    fn range(&self, range: (Bound<String>, Bound<String>)) -> Result<Vec<(String, String)>> {
+        if is_empty_range(&range) {
+            return Ok(Vec::new());
+        }
+        Ok(self.sorted_pairs(|key| range.contains(key)))
+    }
+
+    fn watch(&self, key: String) -> Result<Receiver<Option<String>>> {
+        Ok(self.watchers.subscribe(key))
+    }
+
+    fn flush_durable(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn stats(&self) -> Result<Stats> {
+        Ok(Stats {
+            live_keys: self.map.read().unwrap().len(),
+            dirt_bytes: 0,
+            log_size_bytes: 0,
+            compaction_threshold: 0,
+        })
+    }
+}
@@ -1,9 +1,148 @@
+use crate::{kvs_error::Result, CommandPosition, KvStoreError};
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fmt;
+
+/// What went wrong with a command, as sent back in `Response::Err`. The client turns it
+/// back into a `KvStoreError` with `Response::into_result`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
+    KeyNotFound,
+    /// The server or its store only serves reads.
+    ReadOnly,
+    /// The connection hasn't authenticated with the server's token.
+    Unauthorized,
+    /// Any other failure, as its message.
+    Internal(String),
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::KeyNotFound => write!(f, "{}", KvStoreError::KeyNotFound),
+            ErrorKind::ReadOnly => write!(f, "read-only"),
+            ErrorKind::Unauthorized => write!(f, "unauthorized"),
+            ErrorKind::Internal(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl From<&KvStoreError> for ErrorKind {
+    fn from(err: &KvStoreError) -> Self {
+        match err {
+            KvStoreError::KeyNotFound => ErrorKind::KeyNotFound,
+            KvStoreError::ReadOnly => ErrorKind::ReadOnly,
+            KvStoreError::Unauthorized => ErrorKind::Unauthorized,
+            err => ErrorKind::Internal(err.to_string()),
+        }
+    }
+}
+
+impl From<ErrorKind> for KvStoreError {
+    fn from(kind: ErrorKind) -> Self {
+        match kind {
+            ErrorKind::KeyNotFound => KvStoreError::KeyNotFound,
+            ErrorKind::ReadOnly => KvStoreError::ReadOnly,
+            ErrorKind::Unauthorized => KvStoreError::Unauthorized,
+            ErrorKind::Internal(message) => KvStoreError::ServerError(message),
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Response {
     GetOk(String),
-    SetOk,
+    /// Where the value was written, for engines with a log.
+    SetOk(Option<CommandPosition>),
     RmOk,
-    Error(String),
+    OpenOk,
+    /// Whether a `Cas` swapped the value.
+    CasOk(bool),
+    /// The value of a key after an `Incr`.
+    IncrOk(i64),
+    /// How many keys a `RemovePrefix` removed.
+    RemovePrefixOk(usize),
+    /// Key/value pairs, sorted by key.
+    Values(Vec<(String, String)>),
+    /// Keys, sorted.
+    Keys(Vec<String>),
+    /// The responses to the commands of a `Batch`, in order.
+    BatchOk(Vec<Response>),
+    Pong,
+    /// Every earlier write is on disk.
+    Flushed,
+    SelectOk,
+    AuthOk,
+    WatchOk,
+    UnwatchOk,
+    /// A watched key's new value, or `None` if it was removed.
+    Changed {
+        key: String,
+        value: Option<String>,
+    },
+    Version(String),
+    Stats {
+        live_keys: usize,
+        dirt_bytes: u64,
+        log_size_bytes: u64,
+    },
+    Err(ErrorKind),
+}
+
+impl Response {
+    /// Renders the response as a JSON object with a `status` of `ok` or `error` and a
+    /// field for whatever it carries, e.g. `{"status":"ok","value":"v"}`.
+    pub fn to_json(&self) -> Value {
+        match self {
+            Response::GetOk(value) => json!({ "status": "ok", "value": value }),
+            Response::SetOk(Some(position)) => json!({ "status": "ok", "position": position }),
+            Response::SetOk(None)
+            | Response::RmOk
+            | Response::OpenOk
+            | Response::Pong
+            | Response::Flushed
+            | Response::SelectOk
+            | Response::AuthOk
+            | Response::WatchOk
+            | Response::UnwatchOk => json!({ "status": "ok" }),
+            Response::CasOk(swapped) => json!({ "status": "ok", "swapped": swapped }),
+            Response::IncrOk(value) => json!({ "status": "ok", "value": value }),
+            Response::RemovePrefixOk(removed) => json!({ "status": "ok", "removed": removed }),
+            Response::Values(pairs) => json!({
+                "status": "ok",
+                "values": pairs
+                    .iter()
+                    .map(|(key, value)| (key.clone(), Value::from(value.as_str())))
+                    .collect::<serde_json::Map<_, _>>(),
+            }),
+            Response::Keys(keys) => json!({ "status": "ok", "keys": keys }),
+            Response::BatchOk(responses) => json!({
+                "status": "ok",
+                "responses": responses.iter().map(Response::to_json).collect::<Vec<_>>(),
+            }),
+            Response::Changed { key, value } => {
+                json!({ "status": "ok", "key": key, "value": value })
+            }
+            Response::Version(version) => json!({ "status": "ok", "version": version }),
+            Response::Stats {
+                live_keys,
+                dirt_bytes,
+                log_size_bytes,
+            } => json!({
+                "status": "ok",
+                "live_keys": live_keys,
+                "dirt_bytes": dirt_bytes,
+                "log_size_bytes": log_size_bytes,
+            }),
+            Response::Err(kind) => json!({ "status": "error", "error": kind.to_string() }),
+        }
+    }
+
+    /// Returns the response, or the error it carries as a `KvStoreError`.
+    pub fn into_result(self) -> Result<Response> {
+        match self {
+            Response::Err(kind) => Err(kind.into()),
+            response => Ok(response),
+        }
+    }
 }
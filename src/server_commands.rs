@@ -1,15 +1,44 @@
 use std::{
-    io::Read,
-    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream},
-    path::PathBuf,
-    process::exit,
+    env::current_dir,
+    fs,
+    io::{self, BufReader, BufWriter},
+    mem,
+    ops::Bound,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError},
+        Arc, Mutex, RwLock, Weak,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
-use crate::{kvs_error::Result, response::Response, KvStoreError};
-use crate::{Command, KvStore};
-use bincode::{deserialize_from, serialize_into};
+use crate::{
+    frame::{try_read_frame, write_frame},
+    kvs_error::Result,
+    response::{ErrorKind, Response},
+    transport::{parse_addr, Addr, Listener, Stream},
+    KvStoreError,
+};
+use crate::{
+    Command, KvStore, KvStoreOptions, KvsEngine, MemoryKvsEngine, SharedQueueThreadPool,
+    SledKvsEngine, ThreadPool,
+};
 use clap::Parser;
-use log::info;
+use log::{debug, error, info, LevelFilter};
+use serde::Deserialize;
+
+const ENGINE_MARKER: &str = "engine";
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+const MIN_POOL_SIZE: u32 = 4;
+/// How long a connection may go without sending a command before it is closed.
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long a connection's watches may take to forward a change, or to notice they have
+/// been stopped.
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// How many keys a connection may watch at once.
+const MAX_WATCHES: usize = 1024;
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
@@ -18,115 +47,693 @@ pub struct ServerArgs {
     pub addr: Option<String>,
     #[clap(short, long)]
     pub engine: Option<String>,
+    /// Where the engine keeps its data, the current directory by default.
+    #[clap(short, long)]
+    pub data_dir: Option<PathBuf>,
+    /// A TOML file with server options. Flags given on the command line override it.
+    #[clap(short, long)]
+    pub config: Option<PathBuf>,
+    /// A token clients must send with `Command::Auth` before anything else.
+    #[clap(long)]
+    pub auth_token: Option<String>,
+    /// The most verbose messages to log, `info` by default. `RUST_LOG` overrides it.
+    #[clap(long, possible_values = ["trace", "debug", "info", "warn", "error"])]
+    pub log_level: Option<LevelFilter>,
+    /// Serve reads only, answering every write with a `read-only` error. The data directory
+    /// is opened without its lock, so a writing server may use it too.
+    #[clap(long)]
+    pub read_only: bool,
+    /// Whether accepted TCP connections skip Nagle's algorithm. Overrides the config file.
+    #[clap(long)]
+    pub tcp_nodelay: Option<bool>,
+}
+
+/// Server options read from the file passed with `--config`. Every field is optional.
+///
+/// ```toml
+/// addr = "127.0.0.1:4000"
+/// engine = "kvs"
+/// data_dir = "/var/lib/kvs"
+/// compaction_threshold = 1048576
+/// tcp_nodelay = true
+/// idle_timeout_secs = 60
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    pub addr: Option<String>,
+    pub engine: Option<String>,
+    pub data_dir: Option<PathBuf>,
+    pub compaction_threshold: Option<u64>,
+    /// Whether accepted TCP connections skip Nagle's algorithm, `true` by default.
+    pub tcp_nodelay: Option<bool>,
+    /// Seconds a connection may idle between commands before it is closed, 60 by
+    /// default. 0 keeps idle connections open.
+    pub idle_timeout_secs: Option<u64>,
+}
+
+impl ServerConfig {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(toml::from_str(&fs::read_to_string(path)?)?)
+    }
 }
 
-#[derive(Debug)]
 pub struct KvsServer {
-    addr: SocketAddr,
-    kvs: KvStore,
-    engine: String,
+    addr: Addr,
+    /// Swapped out by `Command::Open`; connections pick up the new engine on their next
+    /// command.
+    engine: Arc<RwLock<Arc<dyn KvsEngine>>>,
+    engine_name: String,
+    /// Used for the engine opened at startup and for every `Command::Open`.
+    options: KvStoreOptions,
+    auth_token: Option<Arc<str>>,
+    read_only: bool,
+    tcp_nodelay: bool,
+    idle_timeout: Option<Duration>,
 }
 
 impl KvsServer {
+    /// Opens the engine and resolves the address to serve on.
+    ///
+    /// `addr`, `engine` and a non-empty `path` take precedence over the file at `config`,
+    /// if any. Without either, the server listens on `127.0.0.1:4000` and keeps the `kvs`
+    /// engine's data in the current directory. An address of the form
+    /// `unix:/path/to.sock` listens on a Unix domain socket instead.
     pub fn new(
         addr: Option<String>,
         engine: Option<String>,
         path: impl Into<PathBuf>,
+        config: Option<PathBuf>,
     ) -> Result<Self> {
-        let sock_addr;
-        let res_engine;
+        Self::open(addr, engine, path.into(), config, false)
+    }
 
-        match addr {
-            Some(addr) => match addr.parse::<SocketAddr>() {
-                Ok(sock) => sock_addr = sock,
-                Err(_) => {
-                    eprintln!("invalid address, dumbass");
-                    exit(1);
-                }
-            },
-            None => sock_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000),
-        }
+    /// Like `new`, but serves reads only, as `read_only(true)` does. The `kvs` engine is
+    /// opened with `KvStore::open_read_only`, so the server can run next to a process
+    /// writing to the same directory, and serves the data as it was at startup.
+    pub fn new_read_only(
+        addr: Option<String>,
+        engine: Option<String>,
+        path: impl Into<PathBuf>,
+        config: Option<PathBuf>,
+    ) -> Result<Self> {
+        Self::open(addr, engine, path.into(), config, true)
+    }
 
-        match engine {
+    fn open(
+        addr: Option<String>,
+        engine: Option<String>,
+        mut path: PathBuf,
+        config: Option<PathBuf>,
+        read_only: bool,
+    ) -> Result<Self> {
+        let config = match config {
+            Some(config) => ServerConfig::load(config)?,
+            None => ServerConfig::default(),
+        };
+        let sock_addr = parse_addr(addr.or(config.addr))?;
+        let res_engine;
+
+        match engine.or(config.engine) {
             Some(name) => match name.as_str() {
                 "kvs" => res_engine = String::from("kvs"),
                 "sled" => res_engine = String::from("sled"),
-                _ => {
-                    eprintln!("invalid engine, my good sir");
-                    exit(1);
-                }
+                "memory" => res_engine = String::from("memory"),
+                _ => return Err(KvStoreError::InvalidEngine(name)),
             },
             None => res_engine = String::from("kvs"),
         }
 
-        let kvs = KvStore::open(path)?;
+        if path.as_os_str().is_empty() {
+            path = match config.data_dir {
+                Some(data_dir) => data_dir,
+                None => current_dir()?,
+            };
+        }
+        let mut options = KvStoreOptions::default();
+        if let Some(threshold) = config.compaction_threshold {
+            options = options.compaction_threshold(threshold);
+        }
+        let engine = open_engine(&res_engine, &options, path, read_only)?;
 
         Ok(Self {
             addr: sock_addr,
-            kvs,
-            engine: res_engine,
+            engine: Arc::new(RwLock::new(engine)),
+            engine_name: res_engine,
+            options,
+            auth_token: None,
+            read_only,
+            tcp_nodelay: config.tcp_nodelay.unwrap_or(true),
+            idle_timeout: match config.idle_timeout_secs {
+                Some(0) => None,
+                Some(secs) => Some(Duration::from_secs(secs)),
+                None => Some(DEFAULT_IDLE_TIMEOUT),
+            },
         })
     }
 
+    /// Requires every connection to start with a `Command::Auth` carrying `token`. Any
+    /// other first command, or a wrong token, is answered with an `unauthorized` error and
+    /// the connection is closed.
+    pub fn with_auth_token(mut self, token: impl Into<String>) -> Self {
+        self.auth_token = Some(token.into().into());
+        self
+    }
+
+    /// Whether to answer every write, including `Command::Open`, with a `read-only` error
+    /// instead of running it. Reads, stats and flushes are served as usual.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Whether accepted TCP connections skip Nagle's algorithm, so each response is sent
+    /// as soon as it is written. On by default, or as set in the config file.
+    pub fn tcp_nodelay(mut self, nodelay: bool) -> Self {
+        self.tcp_nodelay = nodelay;
+        self
+    }
+
+    /// How long a connection may go without sending a command before it is closed, or
+    /// `None` to keep idle connections open. A minute by default, or as set in the config
+    /// file.
+    ///
+    /// Each connection holds a worker of the pool while it is open, so without a timeout
+    /// a few idle clients are enough to keep every other client waiting. A client that
+    /// only watches keys has to send something, such as `Command::Ping`, in time.
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Serves connections forever, handling each one on a thread pool.
+    ///
+    /// Only binding can fail. A connection that fails, even while being accepted, is
+    /// logged and dropped without affecting the others.
     pub fn run(&mut self) -> Result<()> {
+        let listener = self.bind()?;
+        let pool = SharedQueueThreadPool::new(pool_size())?;
+        loop {
+            match self.accept(&listener) {
+                Ok(stream) => self.dispatch(&pool, stream),
+                Err(err) => accept_failed(&err),
+            }
+        }
+    }
+
+    /// Serves connections until `stop` is set, then returns.
+    ///
+    /// The flag is checked between connections, so a request in flight is always
+    /// completed, and every write has been flushed by the engine before it responds.
+    pub fn run_until(&mut self, stop: Arc<AtomicBool>) -> Result<()> {
+        let listener = self.bind()?;
+        listener.set_nonblocking(true)?;
+        let pool = SharedQueueThreadPool::new(pool_size())?;
+        while !stop.load(Ordering::SeqCst) {
+            match self.accept(&listener) {
+                Ok(stream) => self.dispatch(&pool, stream),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(ACCEPT_POLL_INTERVAL);
+                }
+                Err(err) => accept_failed(&err),
+            }
+        }
+        info!("Server on {} shutting down", self.addr);
+        Ok(())
+    }
+
+    /// Accepts a connection and applies the socket options to it.
+    fn accept(&self, listener: &Listener) -> io::Result<Stream> {
+        let stream = listener.accept()?;
+        if let Err(err) = stream.set_nodelay(self.tcp_nodelay) {
+            error!("Failed to set TCP_NODELAY: {}", err);
+        }
+        stream.set_read_timeout(self.idle_timeout)?;
+        Ok(stream)
+    }
+
+    fn dispatch(&self, pool: &impl ThreadPool, stream: Stream) {
+        let engine = self.engine.clone();
+        let engine_name = self.engine_name.clone();
+        let options = self.options.clone();
+        let auth_token = self.auth_token.clone();
+        let read_only = self.read_only;
+        pool.spawn(move || {
+            if let Err(err) = handle_stream(
+                &engine,
+                &engine_name,
+                &options,
+                auth_token.as_deref(),
+                read_only,
+                stream,
+            ) {
+                match &err {
+                    KvStoreError::IoError(io_err) if is_disconnect(io_err) => {
+                        info!("Client disconnected: {}", err)
+                    }
+                    KvStoreError::IoError(io_err) if is_timeout(io_err) => {
+                        info!("Closed idle connection")
+                    }
+                    _ => error!("Failed to handle connection: {}", err),
+                }
+            }
+        });
+    }
+
+    fn bind(&self) -> Result<Listener> {
         info!(env!("CARGO_PKG_VERSION"));
         info!(
             "Server listening on {}, via the engine {}",
-            self.addr, self.engine
+            self.addr, self.engine_name
         );
-        let listener = TcpListener::bind(self.addr)?;
-        for stream in listener.incoming() {
-            self.handle_stream(stream?)?;
-        }
-        Ok(())
+        Ok(Listener::bind(&self.addr)?)
     }
+}
 
-    fn handle_stream(&mut self, stream: TcpStream) -> Result<()> {
-        let cmd = deserialize_from::<_, Command>(&stream)?;
-        println!("{:?}", cmd);
-        match cmd {
-            Command::Set { key, value } => {
-                self.kvs.set(key.into(), value.into())?;
-                serialize_into(stream, &Response::SetOk)?;
+fn handle_stream(
+    shared_engine: &RwLock<Arc<dyn KvsEngine>>,
+    engine_name: &str,
+    options: &KvStoreOptions,
+    auth_token: Option<&str>,
+    read_only: bool,
+    stream: Stream,
+) -> Result<()> {
+    // Shared with the thread forwarding watched changes
+    let writer = Arc::new(Mutex::new(BufWriter::new(stream.try_clone()?)));
+    let mut reader = BufReader::new(stream);
+    let mut authorized = auth_token.is_none();
+    let mut watches: Option<Watches> = None;
+    let mut selected = SelectedDb::default();
+    loop {
+        // The client closing its end between commands ends the session
+        let cmd = match try_read_frame::<_, Command>(&mut reader)? {
+            Some(cmd) => cmd,
+            None => return Ok(()),
+        };
+        if let Command::Auth { token } = &cmd {
+            debug!("Received auth");
+            let accepted = auth_token
+                .is_none_or(|expected| constant_time_eq(token.as_bytes(), expected.as_bytes()));
+            if accepted {
+                authorized = true;
+                write_frame(&mut *writer.lock().unwrap(), &Response::AuthOk)?;
+                continue;
             }
-            Command::Get { key } => match self.kvs.get(key.to_string()) {
-                Ok(res) => match res {
-                    Some(value) => {
-                        println!("{}", value.clone());
-                        serialize_into(stream, &Response::GetOk(value))?;
-                    }
-                    None => {
-                        println!("{}", KvStoreError::KeyNotFound);
-                        serialize_into(
-                            stream,
-                            &Response::Error(format!("{}", KvStoreError::KeyNotFound)),
-                        )?;
+            authorized = false;
+        }
+        if !authorized {
+            info!("command={} status=unauthorized", cmd.name());
+            write_frame(
+                &mut *writer.lock().unwrap(),
+                &Response::Err(ErrorKind::Unauthorized),
+            )?;
+            return Ok(());
+        }
+        debug!("Received {:?}", cmd);
+        let started = Instant::now();
+        let name = cmd.name();
+        let key = cmd.key().unwrap_or("-").to_owned();
+
+        let mut watched = None;
+        let response = match cmd {
+            Command::Watch { key } => {
+                let watches = watches.get_or_insert_with(|| Watches::start(writer.clone()));
+                let base = shared_engine.read().unwrap().clone();
+                if watches.contains(&base, selected.db, &key) {
+                    Response::WatchOk
+                } else if watches.keys.len() >= MAX_WATCHES {
+                    Response::Err(ErrorKind::Internal("too many watches".to_owned()))
+                } else {
+                    match selected
+                        .engine(shared_engine)
+                        .and_then(|engine| engine.watch(key.clone()))
+                    {
+                        Ok(changes) => {
+                            watches
+                                .keys
+                                .push((Arc::downgrade(&base), selected.db, key.clone()));
+                            watched = Some((key, changes));
+                            Response::WatchOk
+                        }
+                        Err(err) => Response::Err(ErrorKind::from(&err)),
                     }
-                },
-                Err(err) => {
-                    println!("{}", err);
-                    serialize_into(stream, &Response::Error(format!("{}", err)))?;
                 }
-            },
-            Command::Rm { key } => match self.kvs.remove(key.into()) {
-                Ok(()) => serialize_into(stream, &Response::RmOk)?,
-                Err(KvStoreError::KeyNotFound) => {
-                    println!("{}", KvStoreError::KeyNotFound);
-                    serialize_into(
-                        stream,
-                        &Response::Error(format!("{}", KvStoreError::KeyNotFound)),
-                    )?;
-                    exit(1);
+            }
+            Command::Unwatch => {
+                watches = None;
+                Response::UnwatchOk
+            }
+            cmd => execute(
+                shared_engine,
+                engine_name,
+                options,
+                read_only,
+                &mut selected,
+                cmd,
+            ),
+        };
+        let status = match &response {
+            Response::Err(_) => "error",
+            _ => "ok",
+        };
+        info!(
+            "command={} key={} status={} elapsed={:?}",
+            name,
+            key,
+            status,
+            started.elapsed()
+        );
+        write_response(&mut writer.lock().unwrap(), &response)?;
+
+        // Forwarded only now, so no change can be sent ahead of `WatchOk`
+        if let (Some(watched), Some(watches)) = (watched, &watches) {
+            // The forwarding thread only goes away with `watches`
+            let _ = watches.added.send(watched);
+        }
+    }
+}
+
+/// Sends `response`, or an error in its place if it is over `MAX_FRAME_LEN`, as a large
+/// scan or batch result can be. The connection stays usable either way.
+fn write_response(writer: &mut BufWriter<Stream>, response: &Response) -> Result<()> {
+    match write_frame(writer, response) {
+        // Only an oversized frame fails with `InvalidInput`, before anything is written
+        Err(KvStoreError::IoError(err)) if err.kind() == io::ErrorKind::InvalidInput => {
+            error!("Failed to send a response: {}", err);
+            write_frame(
+                writer,
+                &Response::Err(ErrorKind::Internal("response too large".to_owned())),
+            )
+        }
+        result => result,
+    }
+}
+
+/// The database a connection works in, with the engine handle scoped to it.
+#[derive(Default)]
+struct SelectedDb {
+    db: u16,
+    /// The engine `scoped` was selected from, and the handle itself. Kept until an `Open`
+    /// swaps the engine, so commands needn't select anew each time.
+    scoped: Option<(Arc<dyn KvsEngine>, Arc<dyn KvsEngine>)>,
+}
+
+impl SelectedDb {
+    /// Returns the current engine, scoped to the selected database unless that is 0.
+    fn engine(&mut self, shared_engine: &RwLock<Arc<dyn KvsEngine>>) -> Result<Arc<dyn KvsEngine>> {
+        let engine = shared_engine.read().unwrap().clone();
+        if self.db == 0 {
+            return Ok(engine);
+        }
+        match &self.scoped {
+            Some((base, scoped)) if Arc::ptr_eq(base, &engine) => Ok(scoped.clone()),
+            _ => {
+                let scoped = engine.select_db(self.db)?;
+                self.scoped = Some((engine, scoped.clone()));
+                Ok(scoped)
+            }
+        }
+    }
+
+    /// Switches to `db`, staying where it was if the engine can't.
+    fn select(&mut self, shared_engine: &RwLock<Arc<dyn KvsEngine>>, db: u16) -> Result<()> {
+        let previous = mem::replace(self, SelectedDb { db, scoped: None });
+        if let Err(err) = self.engine(shared_engine) {
+            *self = previous;
+            return Err(err);
+        }
+        Ok(())
+    }
+}
+
+/// A watched key and the channel its changes arrive on.
+type Watch = (String, Receiver<Option<String>>);
+
+/// The watches of a connection, whose changes a single thread forwards to the client.
+/// Dropping it, on `Unwatch` or when the connection ends, stops them all.
+struct Watches {
+    /// Each watched key with the engine and database it was watched in, so watching it
+    /// again doesn't forward its changes twice. Weak, so an engine swapped out by `Open`
+    /// can still close.
+    keys: Vec<(Weak<dyn KvsEngine>, u16, String)>,
+    /// Hands new watches to the forwarding thread.
+    added: Sender<Watch>,
+    stop: Arc<AtomicBool>,
+}
+
+impl Watches {
+    fn start(writer: Arc<Mutex<BufWriter<Stream>>>) -> Self {
+        let (added, watches) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        thread::spawn(move || forward_changes(watches, &writer, &thread_stop));
+        Self {
+            keys: Vec::new(),
+            added,
+            stop,
+        }
+    }
+
+    /// Whether `key` is watched in `db` of `engine`. Forgets the watches of engines that
+    /// have closed since.
+    fn contains(&mut self, engine: &Arc<dyn KvsEngine>, db: u16, key: &str) -> bool {
+        self.keys
+            .retain(|(watched, _, _)| watched.strong_count() > 0);
+        let engine = Arc::downgrade(engine);
+        self.keys.iter().any(|(watched, watched_db, watched_key)| {
+            watched.ptr_eq(&engine) && *watched_db == db && watched_key == key
+        })
+    }
+}
+
+impl Drop for Watches {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Sends every change to a watched key to the client as `Response::Changed` until `stop`
+/// is set. Watches arrive on `added`.
+///
+/// `stop` is checked under the writer lock, so nothing follows the response to the
+/// `Unwatch` that set it.
+fn forward_changes(added: Receiver<Watch>, writer: &Mutex<BufWriter<Stream>>, stop: &AtomicBool) {
+    let mut watched: Vec<Watch> = Vec::new();
+    loop {
+        let mut forwarded = false;
+        let mut next = 0;
+        while next < watched.len() {
+            let (key, changes) = &watched[next];
+            let value = match changes.try_recv() {
+                Ok(value) => value,
+                Err(TryRecvError::Empty) => {
+                    next += 1;
+                    continue;
                 }
-                Err(err) => {
-                    serialize_into(stream, &Response::Error(format!("{}", err)))?;
-                    return Err(err);
+                Err(TryRecvError::Disconnected) => {
+                    watched.swap_remove(next);
+                    continue;
                 }
-            },
-            Command::Open { path: _ } => {
-                unimplemented!();
+            };
+            let mut writer = writer.lock().unwrap();
+            if stop.load(Ordering::SeqCst) {
+                return;
             }
+            let change = Response::Changed {
+                key: key.clone(),
+                value,
+            };
+            if let Err(err) = write_frame(&mut *writer, &change) {
+                debug!("Stopped watching: {}", err);
+                return;
+            }
+            forwarded = true;
+            next += 1;
         }
-        Ok(())
+        if forwarded {
+            continue;
+        }
+        // Nothing to forward, so wait for a new watch instead
+        match added.recv_timeout(WATCH_POLL_INTERVAL) {
+            Ok(watch) => watched.push(watch),
+            Err(RecvTimeoutError::Timeout) if !stop.load(Ordering::SeqCst) => {}
+            Err(_) => return,
+        }
+    }
+}
+
+/// Runs `cmd` against the current engine, turning any failure into `Response::Err`.
+fn execute(
+    shared_engine: &RwLock<Arc<dyn KvsEngine>>,
+    engine_name: &str,
+    options: &KvStoreOptions,
+    read_only: bool,
+    selected: &mut SelectedDb,
+    cmd: Command,
+) -> Response {
+    if read_only && cmd.is_write() {
+        return Response::Err(ErrorKind::ReadOnly);
+    }
+    let engine = match selected.engine(shared_engine) {
+        Ok(engine) => engine,
+        Err(err) => return Response::Err(ErrorKind::from(&err)),
+    };
+    let result = match cmd {
+        Command::Set { key, value } => engine.set_with_position(key, value).map(Response::SetOk),
+        Command::Get { key } => engine
+            .get(key)
+            .and_then(|value| value.map(Response::GetOk).ok_or(KvStoreError::KeyNotFound)),
+        Command::Rm { key } => engine.remove(key).map(|()| Response::RmOk),
+        Command::Cas { key, expected, new } => engine
+            .compare_and_swap(key, expected, new)
+            .map(Response::CasOk),
+        Command::Incr { key, delta } => engine.incr(key, delta).map(Response::IncrOk),
+        Command::PrefixScan { prefix } => engine.prefix_scan(&prefix).map(Response::Values),
+        Command::RemovePrefix { prefix } => {
+            engine.remove_prefix(&prefix).map(Response::RemovePrefixOk)
+        }
+        Command::Scan { start, end } => engine
+            .range((
+                start.map_or(Bound::Unbounded, Bound::Included),
+                end.map_or(Bound::Unbounded, Bound::Excluded),
+            ))
+            .map(Response::Values),
+        Command::Open { path } => open_engine(engine_name, options, path, false).map(|reopened| {
+            *shared_engine.write().unwrap() = reopened;
+            Response::OpenOk
+        }),
+        Command::Batch { commands } => {
+            if commands
+                .iter()
+                .any(|cmd| matches!(cmd, Command::Batch { .. }))
+            {
+                Err(KvStoreError::NestedBatch)
+            } else {
+                Ok(Response::BatchOk(
+                    commands
+                        .into_iter()
+                        .map(|cmd| {
+                            execute(
+                                shared_engine,
+                                engine_name,
+                                options,
+                                read_only,
+                                selected,
+                                cmd,
+                            )
+                        })
+                        .collect(),
+                ))
+            }
+        }
+        Command::Ping => Ok(Response::Pong),
+        Command::Flush => engine.flush_durable().map(|()| Response::Flushed),
+        Command::Select { db } => selected
+            .select(shared_engine, db)
+            .map(|()| Response::SelectOk),
+        Command::Version => Ok(Response::Version(env!("CARGO_PKG_VERSION").to_owned())),
+        Command::Stats => engine.stats().map(|stats| Response::Stats {
+            live_keys: stats.live_keys,
+            dirt_bytes: stats.dirt_bytes,
+            log_size_bytes: stats.log_size_bytes,
+        }),
+        Command::Auth { .. }
+        | Command::Watch { .. }
+        | Command::Unwatch
+        | Command::Repl
+        | Command::SetBytes { .. }
+        | Command::SetCompressed { .. }
+        | Command::SetWithTtl { .. } => Ok(Response::Err(ErrorKind::Internal(
+            "Unsupported command".to_owned(),
+        ))),
+    };
+    result.unwrap_or_else(|err| Response::Err(ErrorKind::from(&err)))
+}
+
+/// Logs a connection that failed before it was accepted, e.g. because the client gave up,
+/// and backs off briefly in case the failure persists, like running out of descriptors.
+fn accept_failed(err: &io::Error) {
+    error!("Failed to accept a connection: {}", err);
+    thread::sleep(ACCEPT_POLL_INTERVAL);
+}
+
+/// Whether `err` means the client went away, possibly in the middle of a command.
+fn is_disconnect(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+    )
+}
+
+/// Whether `err` is a read running into the idle timeout, which reports `WouldBlock` on
+/// Unix and `TimedOut` on Windows.
+fn is_timeout(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+/// Compares two tokens in time that depends only on their lengths, not on where they
+/// first differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// One worker per core, but never fewer than `MIN_POOL_SIZE`: a worker stays with its
+/// connection until it closes or idles out, so a single core mustn't mean a single client.
+fn pool_size() -> u32 {
+    thread::available_parallelism().map_or(MIN_POOL_SIZE, |threads| {
+        (threads.get() as u32).max(MIN_POOL_SIZE)
+    })
+}
+
+/// Opens the engine called `name` in `dir`, which must be an existing writable directory.
+///
+/// The memory engine ignores `dir` and starts out empty.
+/// Opens the engine `name` on `dir`. With `read_only`, the `kvs` engine is opened without
+/// taking the directory lock and nothing in `dir` is written.
+fn open_engine(
+    name: &str,
+    options: &KvStoreOptions,
+    dir: PathBuf,
+    read_only: bool,
+) -> Result<Arc<dyn KvsEngine>> {
+    if name == "memory" {
+        return Ok(Arc::new(MemoryKvsEngine::new()));
+    }
+    match fs::metadata(&dir) {
+        Ok(metadata) if metadata.is_dir() && (read_only || !metadata.permissions().readonly()) => {}
+        _ => return Err(KvStoreError::InvalidDirectory(dir.display().to_string())),
+    }
+    check_engine_marker(&dir, name, read_only)?;
+
+    Ok(match name {
+        "sled" => Arc::new(SledKvsEngine::open(dir)?),
+        _ if read_only => Arc::new(KvStore::open_read_only(dir)?),
+        _ => Arc::new(KvStore::open_with_options(dir, options.clone())?),
+    })
+}
+
+/// Records the engine used for `dir` on first start, unless `read_only`, and refuses to
+/// reuse the directory with a different engine afterwards.
+fn check_engine_marker(dir: &Path, engine: &str, read_only: bool) -> Result<()> {
+    let marker = dir.join(ENGINE_MARKER);
+    match fs::read_to_string(&marker) {
+        Ok(persisted) if persisted != engine => Err(KvStoreError::EngineMismatch {
+            persisted,
+            requested: engine.to_owned(),
+        }),
+        Ok(_) => Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            if !read_only {
+                fs::write(marker, engine)?;
+            }
+            Ok(())
+        }
+        Err(err) => Err(err.into()),
     }
 }
@@ -0,0 +1,169 @@
+use crate::{
+    engine::{KvsEngine, Stats},
+    kvs::{add_to_integer, is_empty_range},
+    kvs_error::Result,
+    watch::Watchers,
+    KvStoreError,
+};
+use sled::Db;
+use std::{
+    env::current_dir,
+    ops::Bound,
+    path::PathBuf,
+    sync::{mpsc::Receiver, Arc, Mutex},
+};
+
+/// A `KvsEngine` backed by the `sled` embedded database.
+///
+/// Watchers only hear about changes made through this engine and its clones, not through
+/// other handles onto the same database.
+#[derive(Debug, Clone)]
+pub struct SledKvsEngine {
+    db: Db,
+    watchers: Watchers,
+    /// Held from a write until its watchers have been told, so they hear about concurrent
+    /// writes in the order these were made.
+    writes: Arc<Mutex<()>>,
+}
+
+impl SledKvsEngine {
+    pub fn new(db: Db) -> Self {
+        Self {
+            db,
+            watchers: Watchers::default(),
+            writes: Arc::default(),
+        }
+    }
+
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let mut path: PathBuf = path.into();
+        if path.as_os_str().is_empty() {
+            path = current_dir()?;
+        }
+
+        Ok(Self::new(sled::open(path)?))
+    }
+}
+
+impl KvsEngine for SledKvsEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let _writes = self.writes.lock().unwrap();
+        self.db.insert(&key, value.as_bytes())?;
+        self.db.flush()?;
+        self.watchers.notify(&key, || Some(value));
+        Ok(())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self
+            .db
+            .get(key)?
+            .map(|value| String::from_utf8(value.to_vec()))
+            .transpose()?)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let _writes = self.writes.lock().unwrap();
+        self.db.remove(&key)?.ok_or(KvStoreError::KeyNotFound)?;
+        self.db.flush()?;
+        self.watchers.notify(&key, || None);
+        Ok(())
+    }
+
+    fn compare_and_swap(&self, key: String, expected: Option<String>, new: String) -> Result<bool> {
+        let _writes = self.writes.lock().unwrap();
+        let swapped = self
+            .db
+            .compare_and_swap(&key, expected.map(String::into_bytes), Some(new.as_bytes()))?
+            .is_ok();
+        self.db.flush()?;
+        if swapped {
+            self.watchers.notify(&key, || Some(new));
+        }
+        Ok(swapped)
+    }
+
+    fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        let _writes = self.writes.lock().unwrap();
+        loop {
+            let current = self.db.get(&key)?;
+            let old_value = current
+                .as_ref()
+                .map(|value| String::from_utf8(value.to_vec()))
+                .transpose()?;
+            let value = add_to_integer(old_value, delta)?;
+            let swapped =
+                self.db
+                    .compare_and_swap(&key, current, Some(value.to_string().into_bytes()))?;
+            if swapped.is_ok() {
+                self.db.flush()?;
+                self.watchers.notify(&key, || Some(value.to_string()));
+                return Ok(value);
+            }
+        }
+    }
+
+    fn prefix_scan(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        self.db
+            .scan_prefix(prefix)
+            .map(|pair| {
+                let (key, value) = pair?;
+                Ok((
+                    String::from_utf8(key.to_vec())?,
+                    String::from_utf8(value.to_vec())?,
+                ))
+            })
+            .collect()
+    }
+
+    fn remove_prefix(&self, prefix: &str) -> Result<usize> {
+        let _writes = self.writes.lock().unwrap();
+        let mut removed = Vec::new();
+        for key in self.db.scan_prefix(prefix).keys() {
+            let key = key?;
+            if self.db.remove(&key)?.is_some() {
+                removed.push(String::from_utf8(key.to_vec())?);
+            }
+        }
+        self.db.flush()?;
+        for key in &removed {
+            self.watchers.notify(key, || None);
+        }
+        Ok(removed.len())
+    }
+
+    fn range(&self, range: (Bound<String>, Bound<String>)) -> Result<Vec<(String, String)>> {
+        if is_empty_range(&range) {
+            return Ok(Vec::new());
+        }
+
+        self.db
+            .range(range)
+            .map(|pair| {
+                let (key, value) = pair?;
+                Ok((
+                    String::from_utf8(key.to_vec())?,
+                    String::from_utf8(value.to_vec())?,
+                ))
+            })
+            .collect()
+    }
+
+    fn watch(&self, key: String) -> Result<Receiver<Option<String>>> {
+        Ok(self.watchers.subscribe(key))
+    }
+
+    fn flush_durable(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn stats(&self) -> Result<Stats> {
+        Ok(Stats {
+            live_keys: self.db.len(),
+            dirt_bytes: 0,
+            log_size_bytes: self.db.size_on_disk()?,
+            compaction_threshold: 0,
+        })
+    }
+}
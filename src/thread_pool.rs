@@ -0,0 +1,67 @@
+use crate::kvs_error::Result;
+use log::error;
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+/// A pool of worker threads that run jobs handed to `spawn`.
+pub trait ThreadPool {
+    fn new(threads: u32) -> Result<Self>
+    where
+        Self: Sized;
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static;
+}
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A `ThreadPool` whose workers pull jobs from a single shared queue.
+///
+/// A panicking job is caught and logged, so it doesn't take its worker down with it.
+/// Dropping the pool closes the queue: workers exit once it is drained, without being
+/// waited on, since a job may be serving a connection that stays open indefinitely.
+pub struct SharedQueueThreadPool {
+    sender: Sender<Job>,
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for _ in 0..threads {
+            let receiver = receiver.clone();
+            thread::Builder::new().spawn(move || run_jobs(&receiver))?;
+        }
+
+        Ok(Self { sender })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.sender
+            .send(Box::new(job))
+            .expect("thread pool has no workers left");
+    }
+}
+
+fn run_jobs(receiver: &Mutex<Receiver<Job>>) {
+    loop {
+        let job = match receiver.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+            error!("A thread pool job panicked");
+        }
+    }
+}
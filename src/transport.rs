@@ -0,0 +1,221 @@
+use crate::{kvs_error::Result, KvStoreError};
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream},
+    time::Duration,
+};
+#[cfg(unix)]
+use std::{
+    fs,
+    os::unix::{
+        fs::FileTypeExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::PathBuf,
+};
+
+/// The prefix that makes an address a Unix domain socket path rather than a TCP address.
+const UNIX_PREFIX: &str = "unix:";
+
+/// Where a server listens and a client connects: a TCP address, or a Unix domain socket
+/// written as `unix:/path/to.sock`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Addr {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl fmt::Display for Addr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Addr::Tcp(addr) => write!(f, "{}", addr),
+            #[cfg(unix)]
+            Addr::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+/// Parses `addr`, falling back to `127.0.0.1:4000` when it is absent.
+pub(crate) fn parse_addr(addr: Option<String>) -> Result<Addr> {
+    let addr = match addr {
+        Some(addr) => addr,
+        None => {
+            return Ok(Addr::Tcp(SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                4000,
+            )))
+        }
+    };
+    #[cfg(unix)]
+    if let Some(path) = addr.strip_prefix(UNIX_PREFIX) {
+        if path.is_empty() {
+            return Err(KvStoreError::InvalidAddress(addr));
+        }
+        return Ok(Addr::Unix(PathBuf::from(path)));
+    }
+    addr.parse()
+        .map(Addr::Tcp)
+        .map_err(|_| KvStoreError::InvalidAddress(addr))
+}
+
+/// A listener on either transport.
+#[derive(Debug)]
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    /// Binds to `addr`. A Unix socket file left behind by an earlier server is replaced,
+    /// but any other file at the path fails with `AddrInUse` and is left alone.
+    ///
+    /// On Unix, TCP listeners are bound with `SO_REUSEADDR`, so a restarted server can
+    /// take its port back while connections of the previous one linger in `TIME_WAIT`.
+    pub(crate) fn bind(addr: &Addr) -> io::Result<Self> {
+        match addr {
+            Addr::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr)?)),
+            #[cfg(unix)]
+            Addr::Unix(path) => {
+                match fs::symlink_metadata(path) {
+                    Ok(metadata) if !metadata.file_type().is_socket() => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::AddrInUse,
+                            format!("{} exists and is not a socket", path.display()),
+                        ));
+                    }
+                    // A socket nobody listens on anymore is stale
+                    Ok(_) if UnixStream::connect(path).is_err() => fs::remove_file(path)?,
+                    _ => {}
+                }
+                Ok(Listener::Unix(UnixListener::bind(path)?, path.clone()))
+            }
+        }
+    }
+
+    pub(crate) fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Listener::Tcp(listener) => listener.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => listener.set_nonblocking(nonblocking),
+        }
+    }
+
+    /// Accepts the next connection, which always blocks regardless of the listener.
+    pub(crate) fn accept(&self) -> io::Result<Stream> {
+        let stream = match self {
+            Listener::Tcp(listener) => Stream::Tcp(listener.accept()?.0),
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => Stream::Unix(listener.accept()?.0),
+        };
+        stream.set_nonblocking(false)?;
+        Ok(stream)
+    }
+}
+
+/// Removes the socket file, so the path can be bound again.
+impl Drop for Listener {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Listener::Unix(_, path) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// A connection over either transport.
+#[derive(Debug)]
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    /// Connects to `addr`. The timeout only bounds TCP connects; a Unix socket either
+    /// accepts or refuses right away.
+    pub(crate) fn connect(addr: &Addr, timeout: Option<Duration>) -> io::Result<Self> {
+        match addr {
+            Addr::Tcp(addr) => match timeout {
+                Some(timeout) => TcpStream::connect_timeout(addr, timeout),
+                None => TcpStream::connect(addr),
+            }
+            .map(Stream::Tcp),
+            #[cfg(unix)]
+            Addr::Unix(path) => UnixStream::connect(path).map(Stream::Unix),
+        }
+    }
+
+    pub(crate) fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Stream::Tcp(stream) => stream.try_clone().map(Stream::Tcp),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.try_clone().map(Stream::Unix),
+        }
+    }
+
+    /// Turns off Nagle's algorithm on a TCP stream when `nodelay` is set, so each small
+    /// frame is sent right away. Unix sockets never delay writes, so this does nothing.
+    pub(crate) fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_nodelay(nodelay),
+            #[cfg(unix)]
+            Stream::Unix(_) => Ok(()),
+        }
+    }
+
+    /// Whether writes skip Nagle's algorithm, which is always the case on a Unix socket.
+    pub(crate) fn nodelay(&self) -> io::Result<bool> {
+        match self {
+            Stream::Tcp(stream) => stream.nodelay(),
+            #[cfg(unix)]
+            Stream::Unix(_) => Ok(true),
+        }
+    }
+
+    pub(crate) fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+
+/// The most recently read values of a store, evicting the least recently used one once
+/// `capacity` keys are cached.
+#[derive(Debug)]
+pub(crate) struct ValueCache {
+    capacity: usize,
+    /// Each cached value and the tick it was last used at.
+    entries: HashMap<String, (Arc<str>, u64)>,
+    /// The cached keys by the tick they were last used at, oldest first.
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+impl ValueCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    /// Returns the cached value of `key`, marking it as the most recently used.
+    pub(crate) fn get(&mut self, key: &str) -> Option<Arc<str>> {
+        self.tick += 1;
+        let (value, used) = self.entries.get_mut(key)?;
+        let key = self.recency.remove(used).expect("cached keys have a tick");
+        *used = self.tick;
+        self.recency.insert(self.tick, key);
+        Some(value.clone())
+    }
+
+    pub(crate) fn insert(&mut self, key: String, value: Arc<str>) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.remove(&key);
+        if self.entries.len() >= self.capacity {
+            if let Some((_, oldest)) = self.recency.pop_first() {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.tick += 1;
+        self.recency.insert(self.tick, key.clone());
+        self.entries.insert(key, (value, self.tick));
+    }
+
+    pub(crate) fn remove(&mut self, key: &str) {
+        if let Some((_, used)) = self.entries.remove(key) {
+            self.recency.remove(&used);
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.recency.clear();
+    }
+}
@@ -0,0 +1,35 @@
+use std::sync::{
+    mpsc::{self, Receiver, Sender},
+    Arc, Mutex,
+};
+
+/// A watched key and the channel its changes go to.
+type Watcher = (String, Sender<Option<String>>);
+
+/// The channels of everyone watching a key, shared by every clone of an engine.
+///
+/// A watcher goes away once its receiver is dropped, the next time its key changes.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Watchers {
+    senders: Arc<Mutex<Vec<Watcher>>>,
+}
+
+impl Watchers {
+    /// Returns a channel receiving the new value of `key` after every change to it, or
+    /// `None` when it is removed.
+    pub(crate) fn subscribe(&self, key: String) -> Receiver<Option<String>> {
+        let (sender, receiver) = mpsc::channel();
+        self.senders.lock().unwrap().push((key, sender));
+        receiver
+    }
+
+    /// Sends the new value of `key` to its watchers. `value` only runs if there are any.
+    pub(crate) fn notify(&self, key: &str, value: impl FnOnce() -> Option<String>) {
+        let mut senders = self.senders.lock().unwrap();
+        if !senders.iter().any(|(watched, _)| watched == key) {
+            return;
+        }
+        let value = value();
+        senders.retain(|(watched, sender)| watched != key || sender.send(value.clone()).is_ok());
+    }
+}
@@ -1,4 +1,17 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    BincodeCodec, BufReaderWithPos, BufWriterWithPos, Command, CompactionStrategy, JsonCodec,
+    KvStore, KvStoreError, KvStoreOptions, KvsEngine, LogCodec, MemoryKvsEngine, Metrics,
+    OpenReport, Result, SledKvsEngine, Stats,
+};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, Cursor, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -6,7 +19,7 @@ use walkdir::WalkDir;
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -16,7 +29,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -27,7 +40,7 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -36,7 +49,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -48,14 +61,14 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -64,7 +77,7 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
@@ -72,7 +85,7 @@ fn remove_non_existent_key() -> Result<()> {
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
@@ -84,7 +97,7 @@ fn remove_key() -> Result<()> {
 #[test]
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -114,7 +127,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -124,3 +137,2252 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Manual compaction should report the reclaimed bytes and keep every key readable
+#[test]
+fn manual_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for iter in 0..100 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+
+    assert!(store.compact()? > 0);
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("99".to_owned()));
+    }
+
+    // Nothing is left to reclaim right after a compaction
+    assert_eq!(store.compact()?, 0);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("99".to_owned()));
+    }
+
+    Ok(())
+}
+
+// A small compaction threshold should compact after a handful of overwrites
+#[test]
+fn custom_compaction_threshold() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().compaction_threshold(200);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let log_size = || {
+        log_files(temp_dir.path())
+            .iter()
+            .map(|path| file_size(path))
+            .sum::<u64>()
+    };
+
+    let mut largest_size = 0;
+    for iter in 0..10 {
+        store.set("key1".to_owned(), format!("value{}", iter))?;
+        let size = log_size();
+        if size < largest_size {
+            return Ok(());
+        }
+        largest_size = size;
+    }
+
+    panic!("No compaction detected");
+}
+
+fn log_files(dir: &Path) -> Vec<PathBuf> {
+    fs::read_dir(dir)
+        .expect("unable to read directory")
+        .map(|entry| entry.expect("unable to read directory entry").path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "log"))
+        .collect()
+}
+
+fn file_size(path: &Path) -> u64 {
+    fs::metadata(path)
+        .expect("unable to read file metadata")
+        .len()
+}
+
+// Compaction should move live data into a new generation next to the active log
+#[test]
+fn generation_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().compaction_threshold(200);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    assert_eq!(
+        log_files(temp_dir.path()),
+        vec![temp_dir.path().join("1.log")]
+    );
+    for iter in 0..10 {
+        store.set("key1".to_owned(), format!("value{}", iter))?;
+        store.set("key2".to_owned(), format!("value{}", iter))?;
+    }
+    assert!(log_files(temp_dir.path()).len() > 1);
+    assert!(!temp_dir.path().join("1.log").exists());
+
+    // Reopen and replay every generation in order
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value9".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value9".to_owned()));
+
+    Ok(())
+}
+
+// A compaction failing partway should leave the index on the original generations and
+// no partial file behind
+#[test]
+fn failed_compaction_keeps_data() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .compaction_strategy(CompactionStrategy::Manual)
+        .max_segment_bytes(1);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key in ["a", "b", "c"] {
+        store.set(key.to_owned(), format!("value-{}", key))?;
+        store.get(key.to_owned())?;
+    }
+    let mut files = log_files(temp_dir.path());
+    files.sort();
+
+    // A fresh clone opens its read handles lazily, so it fails on "b" after copying "a"
+    let clone = store.clone();
+    let missing = temp_dir.path().join("2.log");
+    let contents = fs::read(&missing).expect("unable to read log file");
+    fs::remove_file(&missing).expect("unable to remove log file");
+    assert!(clone.compact().is_err());
+    fs::write(&missing, contents).expect("unable to restore log file");
+
+    let mut after = log_files(temp_dir.path());
+    after.sort();
+    assert_eq!(after, files);
+    assert_eq!(
+        fs::read_dir(temp_dir.path())
+            .expect("unable to read directory")
+            .count(),
+        files.len() + 1 // the lock file
+    );
+    for key in ["a", "b", "c"] {
+        assert_eq!(store.get(key.to_owned())?, Some(format!("value-{}", key)));
+    }
+
+    // Once the file is back, compaction goes through
+    clone.compact()?;
+    assert!(!temp_dir.path().join("1.log").exists());
+    for key in ["a", "b", "c"] {
+        assert_eq!(store.get(key.to_owned())?, Some(format!("value-{}", key)));
+    }
+
+    Ok(())
+}
+
+// Writes after an automatic compaction should land at the end of the new active file
+#[test]
+fn set_after_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().compaction_threshold(200);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for iter in 0..100 {
+        store.set("key1".to_owned(), format!("value{}", iter))?;
+    }
+    assert!(!temp_dir.path().join("1.log").exists());
+
+    for key in ["key2", "key3"] {
+        let position = store
+            .set_with_position(key.to_owned(), "value".to_owned())?
+            .expect("KvStore reports positions");
+        assert_eq!(
+            position.start + position.length,
+            file_size(&temp_dir.path().join(format!("{}.log", position.gen)))
+        );
+    }
+    assert_eq!(store.get("key1".to_owned())?, Some("value99".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// A snapshot should open as a copy of the store at the time it was taken
+#[test]
+fn snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let snapshot_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..10 {
+        store.set("key1".to_owned(), format!("value{}", iter))?;
+    }
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key3".to_owned())?;
+
+    let dest = snapshot_dir.path().join("backup");
+    store.snapshot(&dest)?;
+    assert_eq!(log_files(&dest), vec![dest.join("1.log")]);
+    // Only the live records are copied
+    assert!(file_size(&dest.join("1.log")) < store.stats()?.log_size_bytes);
+    // A second snapshot won't overwrite the first
+    assert!(matches!(
+        store.snapshot(&dest),
+        Err(KvStoreError::InvalidPath { .. })
+    ));
+
+    store.set("key1".to_owned(), "changed".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+
+    let snapshot = KvStore::open(&dest)?;
+    assert_eq!(
+        snapshot.range(..)?,
+        pairs(&[("key1", "value9"), ("key2", "value2")])
+    );
+    assert_eq!(store.get("key1".to_owned())?, Some("changed".to_owned()));
+
+    Ok(())
+}
+
+// Compaction should keep the newest superseded generations aside without replaying them
+#[test]
+fn keep_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .compaction_strategy(CompactionStrategy::Manual)
+        .keep_generations(2);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("removed".to_owned(), "value".to_owned())?;
+    store.remove("removed".to_owned())?;
+    for round in 0..4 {
+        for iter in 0..10 {
+            store.set("key".to_owned(), format!("value{}-{}", round, iter))?;
+        }
+        store.compact()?;
+    }
+
+    let live = store.list_generations()?;
+    let retained = store.retained_generations()?;
+    assert_eq!(retained.len(), 2);
+    // The compacted generation and the active one after it
+    assert_eq!(live.len(), 2);
+    assert!(retained.iter().all(|gen| gen < &live[0]));
+    for gen in &retained {
+        assert!(temp_dir.path().join(format!("{}.log.old", gen)).exists());
+    }
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key".to_owned())?, Some("value3-9".to_owned()));
+    assert_eq!(store.get("removed".to_owned())?, None);
+    assert_eq!(store.retained_generations()?, retained);
+
+    Ok(())
+}
+
+// The same key in two databases should hold independent values, and `keys` and `clear`
+// should stay within the selected database
+#[test]
+fn numbered_databases() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db0 = KvStore::open(temp_dir.path())?;
+    let mut db1 = db0.clone();
+    db1.with_db(1);
+    assert_eq!(db1.db(), 1);
+
+    db0.set("key".to_owned(), "value0".to_owned())?;
+    db1.set("key".to_owned(), "value1".to_owned())?;
+    db1.set("other".to_owned(), "value1".to_owned())?;
+    assert_eq!(db0.get("key".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(db1.get("key".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(db0.keys().collect::<Vec<_>>(), vec!["key".to_owned()]);
+    assert_eq!(
+        db1.keys().collect::<Vec<_>>(),
+        vec!["key".to_owned(), "other".to_owned()]
+    );
+    assert_eq!(db0.len(), 1);
+    assert_eq!(db1.prefix_scan("")?.len(), 2);
+
+    db1.remove("key".to_owned())?;
+    assert_eq!(db0.get("key".to_owned())?, Some("value0".to_owned()));
+    db1.set("key".to_owned(), "value1".to_owned())?;
+
+    db0.clear()?;
+    assert!(db0.is_empty());
+    assert_eq!(db1.get("key".to_owned())?, Some("value1".to_owned()));
+
+    // Databases survive reopening, and a clone moved back to 0 sees its data again
+    db0.set("key".to_owned(), "value0".to_owned())?;
+    drop(db0);
+    drop(db1);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value0".to_owned()));
+    store.with_db(1).clear()?;
+    assert!(store.is_empty());
+    assert_eq!(
+        store.with_db(0).get("key".to_owned())?,
+        Some("value0".to_owned())
+    );
+
+    Ok(())
+}
+
+// Keys starting with NUL are reserved for database prefixes, so every way of setting one
+// should be refused before anything is written
+#[test]
+fn reserved_keys_rejected() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db0 = KvStore::open(temp_dir.path())?;
+    let mut db1 = db0.clone();
+    db1.with_db(1);
+
+    // Would read back as database 1's "key"
+    let forged = "\u{0}1\u{0}key".to_owned();
+    let value = || "value".to_owned();
+    fn reserved<T>(result: Result<T>) -> bool {
+        matches!(result, Err(KvStoreError::ReservedKey))
+    }
+    for store in [&db0, &db1] {
+        assert!(reserved(store.set(forged.clone(), value())));
+        assert!(reserved(store.set_with_ttl(
+            forged.clone(),
+            value(),
+            Duration::from_secs(60)
+        )));
+        assert!(reserved(store.set_bytes(forged.clone(), vec![1])));
+        assert!(reserved(store.set_and_get(forged.clone(), value())));
+        assert!(reserved(store.get_or_insert_with(forged.clone(), value)));
+        assert!(reserved(store.compare_and_swap(
+            forged.clone(),
+            None,
+            value()
+        )));
+        assert!(reserved(store.incr(forged.clone(), 1)));
+        assert!(reserved(store.set_many(vec![
+            ("key".to_owned(), value()),
+            ("\u{0}abc".to_owned(), value()),
+        ])));
+    }
+    assert!(db0.is_empty());
+    assert!(db1.is_empty());
+    assert_eq!(db0.stats()?.log_size_bytes, 0);
+
+    Ok(())
+}
+
+// `set_and_get` should return the value being replaced
+#[test]
+fn set_and_get_previous_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(
+        store.set_and_get("key1".to_owned(), "value1".to_owned())?,
+        None
+    );
+    assert_eq!(
+        store.set_and_get("key1".to_owned(), "value2".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    store.remove("key1".to_owned())?;
+    assert_eq!(
+        store.set_and_get("key1".to_owned(), "value3".to_owned())?,
+        None
+    );
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn contains_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert!(!store.contains_key("key1"));
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(store.contains_key("key1"));
+    store.remove("key1".to_owned())?;
+    assert!(!store.contains_key("key1"));
+
+    Ok(())
+}
+
+// Removed keys should not be counted
+#[test]
+fn len_counts_live_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.is_empty());
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.len(), 2);
+    assert!(!store.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn keys_sorted() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for key in ["delta", "alpha", "charlie", "bravo"] {
+        store.set(key.to_owned(), "value".to_owned())?;
+    }
+    store.remove("charlie".to_owned())?;
+
+    assert_eq!(
+        store.keys().collect::<Vec<_>>(),
+        vec!["alpha".to_owned(), "bravo".to_owned(), "delta".to_owned()]
+    );
+
+    Ok(())
+}
+
+fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+    pairs
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+#[test]
+fn range_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for key in ["m", "c", "a", "z", "b"] {
+        store.set(key.to_owned(), format!("{}-value", key))?;
+    }
+
+    assert_eq!(
+        store.range("a".to_owned().."m".to_owned())?,
+        pairs(&[("a", "a-value"), ("b", "b-value"), ("c", "c-value")])
+    );
+    assert_eq!(
+        store.range("b".to_owned()..="m".to_owned())?,
+        pairs(&[("b", "b-value"), ("c", "c-value"), ("m", "m-value")])
+    );
+    assert_eq!(
+        store.range("m".to_owned()..)?,
+        pairs(&[("m", "m-value"), ("z", "z-value")])
+    );
+    assert_eq!(store.range(..)?.len(), 5);
+    assert!(store.range("d".to_owned().."l".to_owned())?.is_empty());
+    assert!(store.range("m".to_owned().."a".to_owned())?.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn prefix_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for key in ["username", "user:2", "user:1", "use", "users", "zebra"] {
+        store.set(key.to_owned(), format!("{}-value", key))?;
+    }
+
+    assert_eq!(
+        store.prefix_scan("user:")?,
+        pairs(&[("user:1", "user:1-value"), ("user:2", "user:2-value")])
+    );
+    assert_eq!(
+        store.prefix_scan("username")?,
+        pairs(&[("username", "username-value")])
+    );
+    assert_eq!(
+        store
+            .prefix_scan("user")?
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect::<Vec<_>>(),
+        vec!["user:1", "user:2", "username", "users"]
+    );
+    assert!(store.prefix_scan("userz")?.is_empty());
+    assert_eq!(store.prefix_scan("")?.len(), 6);
+
+    Ok(())
+}
+
+// Removing a key only if it exists should report whether it did, without erroring
+#[test]
+fn remove_if_exists() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    assert!(store.remove_if_exists("key1".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(!store.remove_if_exists("key1".to_owned())?);
+    assert!(!store.remove_if_exists("key2".to_owned())?);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// Entries should contain every live pair, sorted by key
+#[test]
+fn entries() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in (0..50).rev() {
+        store.set(format!("key{:02}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key10".to_owned())?;
+
+    let expected: Vec<(String, String)> = (0..50)
+        .filter(|&key_id| key_id != 10)
+        .map(|key_id| (format!("key{:02}", key_id), format!("value{}", key_id)))
+        .collect();
+    assert_eq!(store.entries()?, expected);
+
+    Ok(())
+}
+
+// Byte values should round-trip exactly, even when they are not valid UTF-8
+#[test]
+fn bytes_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let value = vec![0, 159, 146, 150, 0, 255, b'a', 0];
+
+    store.set_bytes("key1".to_owned(), value.clone())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get_bytes("key1".to_owned())?, Some(value.clone()));
+    assert_eq!(
+        store.get_bytes("key2".to_owned())?,
+        Some(b"value2".to_vec())
+    );
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert!(store.get("key1".to_owned()).is_err());
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes("key1".to_owned())?, Some(value));
+    assert_eq!(store.get_bytes("key3".to_owned())?, None);
+
+    Ok(())
+}
+
+// Writes in sync mode should be visible to an independent reader right away
+#[test]
+fn sync_on_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().sync_on_write(true);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    drop(store);
+    let reader = KvStore::open(temp_dir.path())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(reader.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// A batch should write every pair and leave no partial record behind
+#[test]
+fn set_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let batch: Vec<(String, String)> = (0..10000)
+        .map(|id| (format!("key{}", id), format!("value{}", id)))
+        .collect();
+    store.set_many(batch.clone())?;
+
+    // The whole batch is on disk once set_many returns
+    let log = fs::read_to_string(temp_dir.path().join("1.log")).expect("unable to read log");
+    assert_eq!(log.matches("\"Set\"").count(), 10000);
+    let last_record = log.lines().last().expect("log should not be empty");
+    assert!(log.ends_with('\n'));
+    assert!(last_record.contains("\"value9999\"}} "));
+
+    for (key, value) in &batch {
+        assert_eq!(store.get(key.clone())?, Some(value.clone()));
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 10000);
+
+    Ok(())
+}
+
+// A key given twice in one batch should get its last value, written once
+#[test]
+fn set_many_last_write_wins() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set_many(vec![
+        ("k".to_owned(), "a".to_owned()),
+        ("other".to_owned(), "c".to_owned()),
+        ("k".to_owned(), "b".to_owned()),
+    ])?;
+    assert_eq!(store.get("k".to_owned())?, Some("b".to_owned()));
+    assert_eq!(store.get("other".to_owned())?, Some("c".to_owned()));
+
+    let log = fs::read_to_string(temp_dir.path().join("1.log")).expect("unable to read log");
+    assert_eq!(log.matches("\"k\"").count(), 1);
+    assert_eq!(store.verify()?.records, 2);
+    assert_eq!(store.stats()?.dirt_bytes, 0);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("k".to_owned())?, Some("b".to_owned()));
+
+    Ok(())
+}
+
+// A batch should survive both an implicit drop and an explicit close
+#[test]
+fn batch_survives_drop() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let batch = |start: usize| -> Vec<(String, String)> {
+        (start..start + 100)
+            .map(|id| (format!("key{}", id), format!("value{}", id)))
+            .collect()
+    };
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_many(batch(0))?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 100);
+    store.set_many(batch(100))?;
+    store.close()?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.entries()?.len(), 200);
+    assert_eq!(store.get("key199".to_owned())?, Some("value199".to_owned()));
+
+    Ok(())
+}
+
+// Compacting on open should shrink the log and keep every key
+#[test]
+fn compact_on_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir_size = || -> u64 {
+        log_files(temp_dir.path())
+            .iter()
+            .map(|path| file_size(path))
+            .sum()
+    };
+    // Keep the store from compacting itself while the log grows
+    let options = KvStoreOptions::default().compaction_ratio(1.0);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for iter in 0..100 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("{}-{}", key_id, iter))?;
+        }
+    }
+    store.close()?;
+    let size_before = dir_size();
+
+    let options = KvStoreOptions::default().compact_on_open(true);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert!(dir_size() < size_before / 10);
+    for key_id in 0..10 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("{}-99", key_id))
+        );
+    }
+
+    Ok(())
+}
+
+// Writes should roll over to new log files once the active one reaches the limit
+#[test]
+fn max_segment_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .max_segment_bytes(1024)
+        .compaction_ratio(1.0);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for key_id in 0..200 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    let segments = log_files(temp_dir.path());
+    assert!(segments.len() > 5);
+    for segment in &segments {
+        // One record past the limit at most
+        assert!(file_size(segment) < 1024 + 100);
+    }
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..200 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn clear() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().compaction_threshold(200);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    for iter in 0..10 {
+        for key_id in 0..3 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    store.clear()?;
+    assert!(store.is_empty());
+    for key_id in 0..3 {
+        assert_eq!(store.get(format!("key{}", key_id))?, None);
+    }
+
+    // The store keeps working after being cleared
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    store.clear()?;
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.len(), 0);
+    assert_eq!(store.get("key4".to_owned())?, None);
+    // Only the fresh generation and the lock file are left
+    assert_eq!(fs::read_dir(temp_dir.path())?.count(), 2);
+
+    Ok(())
+}
+
+// A clear cut short before deleting every old generation should still be a clear
+#[test]
+fn clear_interrupted() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    // As if a crash hit after the marker and the new generation were written, and only
+    // the first of two old generations was deleted
+    fs::copy(temp_dir.path().join("1.log"), temp_dir.path().join("2.log"))?;
+    fs::remove_file(temp_dir.path().join("1.log"))?;
+    fs::write(temp_dir.path().join("3.log"), "")?;
+    fs::write(temp_dir.path().join("3.log.cleared"), "")?;
+
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(
+        log_files(temp_dir.path()),
+        vec![temp_dir.path().join("3.log")]
+    );
+    assert!(!temp_dir.path().join("3.log.cleared").exists());
+
+    Ok(())
+}
+
+// Clones should share data while reading and writing from several threads
+#[test]
+fn concurrent_clones() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().compaction_threshold(4096);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for iter in 0..100 {
+                    let key = format!("key{}", thread_id);
+                    store.set(key.clone(), format!("value{}", iter))?;
+                    assert_eq!(store.get(key)?, Some(format!("value{}", iter)));
+                    store.get(format!("key{}", (thread_id + 1) % 8))?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().expect("thread panicked")?;
+    }
+
+    for thread_id in 0..8 {
+        assert_eq!(
+            store.get(format!("key{}", thread_id))?,
+            Some("value99".to_owned())
+        );
+    }
+
+    Ok(())
+}
+
+// A record cut short by a crash should be dropped when reopening
+#[test]
+fn truncated_log_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let file = OpenOptions::new().write(true).open(&log)?;
+    file.set_len(file_size(&log) - 3)?;
+    drop(file);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // Writes after the recovered tail should be readable and survive the next reopen
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Damage before the end of the log should be reported with its offset
+#[test]
+fn corrupt_log_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..3 {
+        store.set(format!("key{}", iter), format!("value{}", iter))?;
+    }
+    drop(store);
+
+    // Every record has the same length, so the second one ends two thirds of the way in.
+    // Records end with a hex checksum and a newline; make the checksum unreadable.
+    let log = temp_dir.path().join("1.log");
+    let record_length = file_size(&log) / 3;
+    let mut bytes = fs::read(&log)?;
+    bytes[2 * record_length as usize - 2] = b'x';
+    fs::write(&log, bytes)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvStoreError::CorruptLog { offset }) => assert_eq!(offset, record_length),
+        _ => panic!("expected a corrupt log error"),
+    }
+
+    Ok(())
+}
+
+// A damaged bincode length before the end of the log should be an error, not a torn
+// tail that takes every later record with it
+#[test]
+fn corrupt_bincode_length() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().codec(BincodeCodec);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for iter in 0..3 {
+        store.set(format!("key{}", iter), format!("value{}", iter))?;
+    }
+    drop(store);
+
+    // The second record's length now runs past the end of the log
+    let log = temp_dir.path().join("1.log");
+    let record_length = file_size(&log) / 3;
+    let mut bytes = fs::read(&log)?;
+    bytes[record_length as usize] = 0xff;
+    fs::write(&log, &bytes)?;
+
+    match KvStore::open_with_options(temp_dir.path(), options) {
+        Err(KvStoreError::ChecksumMismatch { offset }) => assert_eq!(offset, record_length),
+        _ => panic!("expected a checksum mismatch"),
+    }
+    assert_eq!(fs::read(&log)?, bytes);
+
+    Ok(())
+}
+
+// Verify should flag exactly the key whose record was damaged
+#[test]
+fn verify() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..3 {
+        store.set(format!("key{}", iter), format!("value{}", iter))?;
+    }
+    let report = store.verify()?;
+    assert!(report.is_ok());
+    assert_eq!(report.records, 3);
+
+    let log = temp_dir.path().join("1.log");
+    let record_length = file_size(&log) / 3;
+    let mut bytes = fs::read(&log)?;
+    let value = bytes
+        .windows(6)
+        .position(|window| window == b"value1")
+        .expect("value should be in the log");
+    bytes[value] = b'V';
+    fs::write(&log, bytes)?;
+
+    let report = store.verify()?;
+    assert!(!report.is_ok());
+    assert_eq!(report.records, 3);
+    assert_eq!(report.corrupt_records, vec![(1, record_length)]);
+    assert_eq!(report.mismatched_keys, vec!["key1".to_owned()]);
+
+    Ok(())
+}
+
+// Keys and values up to the limits should be accepted, and longer ones rejected without
+// touching the log
+#[test]
+fn size_limits() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .max_key_bytes(8)
+        .max_value_bytes(16);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    store.set("k".repeat(8), "v".repeat(16))?;
+    let log = temp_dir.path().join("1.log");
+    let log_size = file_size(&log);
+
+    match store.set("k".repeat(9), "value".to_owned()) {
+        Err(KvStoreError::KeyTooLarge { size: 9, max: 8 }) => {}
+        result => panic!("expected KeyTooLarge, got {:?}", result),
+    }
+    match store.set("key".to_owned(), "v".repeat(17)) {
+        Err(KvStoreError::ValueTooLarge { size: 17, max: 16 }) => {}
+        result => panic!("expected ValueTooLarge, got {:?}", result),
+    }
+    assert!(matches!(
+        store.set_bytes("key".to_owned(), vec![0; 17]),
+        Err(KvStoreError::ValueTooLarge { .. })
+    ));
+    assert!(matches!(
+        store.set_many(vec![
+            ("key1".to_owned(), "value1".to_owned()),
+            ("key2".to_owned(), "v".repeat(17)),
+        ]),
+        Err(KvStoreError::ValueTooLarge { .. })
+    ));
+
+    assert_eq!(file_size(&log), log_size);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("k".repeat(8))?, Some("v".repeat(16)));
+
+    Ok(())
+}
+
+// Removing a prefix should remove only the keys under it, also after a reopen
+#[test]
+fn remove_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for id in 1..=5 {
+        store.set(format!("sess:{}", id), format!("session{}", id))?;
+    }
+    for key in ["sess", "session", "user:1", "a"] {
+        store.set(key.to_owned(), "other".to_owned())?;
+    }
+
+    assert_eq!(store.remove_prefix("sess:")?, 5);
+    let remaining = vec!["a", "sess", "session", "user:1"];
+    assert_eq!(store.keys().collect::<Vec<_>>(), remaining);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.keys().collect::<Vec<_>>(), remaining);
+
+    Ok(())
+}
+
+// A directory that can't exist should be reported along with its path
+#[test]
+fn open_invalid_path() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let file = temp_dir.path().join("file");
+    fs::write(&file, "not a directory")?;
+    let dir = file.join("store");
+
+    match KvStore::open(&dir) {
+        Err(err @ KvStoreError::InvalidPath { .. }) => {
+            let message = err.to_string();
+            assert!(message.contains(&dir.display().to_string()), "{}", message);
+        }
+        result => panic!("expected InvalidPath, got {:?}", result),
+    }
+    match KvStore::open_read_only(temp_dir.path().join("missing")) {
+        Err(KvStoreError::InvalidPath { path, .. }) => {
+            assert_eq!(path, temp_dir.path().join("missing"))
+        }
+        result => panic!("expected InvalidPath, got {:?}", result),
+    }
+
+    Ok(())
+}
+
+// The estimate should count the overwritten records, and match what compaction frees
+#[test]
+fn compaction_estimate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().compaction_ratio(1.0);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("old{}", key_id))?;
+    }
+    let dead_bytes = file_size(&temp_dir.path().join("1.log"));
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("new{}", key_id))?;
+    }
+
+    let estimate = store.compaction_estimate()?;
+    assert_eq!(estimate.current_bytes, dead_bytes * 2);
+    assert_eq!(estimate.live_bytes, dead_bytes);
+    assert_eq!(estimate.reclaimable_bytes, dead_bytes);
+    assert_eq!(store.len(), 10);
+
+    assert_eq!(store.compact()?, estimate.reclaimable_bytes);
+    assert_eq!(store.compaction_estimate()?.reclaimable_bytes, 0);
+
+    Ok(())
+}
+
+// The sweeper should remove expired keys that are never read, and stop with the store
+#[test]
+fn expiry_sweep() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().expiry_sweep_interval(Duration::from_millis(50));
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    let changes = store.watch("short0".to_owned())?;
+    for key_id in 0..3 {
+        store.set_with_ttl(
+            format!("short{}", key_id),
+            "value".to_owned(),
+            Duration::from_millis(10),
+        )?;
+    }
+    store.set_with_ttl(
+        "long".to_owned(),
+        "value".to_owned(),
+        Duration::from_secs(60),
+    )?;
+    store.set("plain".to_owned(), "value".to_owned())?;
+    // Setting a key again without a TTL keeps it
+    store.set_with_ttl(
+        "renewed".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(10),
+    )?;
+    store.set("renewed".to_owned(), "value".to_owned())?;
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(
+        store.keys().collect::<Vec<_>>(),
+        vec!["long", "plain", "renewed"]
+    );
+    assert_eq!(store.remove_expired()?, 0);
+    assert_eq!(store.metrics().removes, 3);
+    // Watchers hear about the sweep like any other removal
+    assert_eq!(
+        changes.try_iter().collect::<Vec<_>>(),
+        vec![Some("value".to_owned()), None]
+    );
+
+    store.set_with_ttl(
+        "reopened".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    // Dropping the last handle stops the sweeper and releases the directory
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    // Keys set with a TTL before reopening are swept as well
+    thread::sleep(Duration::from_millis(400));
+    assert_eq!(store.metrics().removes, 1);
+    assert_eq!(
+        store.keys().collect::<Vec<_>>(),
+        vec!["long", "plain", "renewed"]
+    );
+
+    Ok(())
+}
+
+// Every counter should match a known sequence of operations
+#[test]
+fn metrics() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.metrics(), Metrics::default());
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_many(vec![
+        ("key2".to_owned(), "value2".to_owned()),
+        ("key3".to_owned(), "value3".to_owned()),
+    ])?;
+    store.get("key1".to_owned())?;
+    store.get("missing".to_owned())?;
+    store.get_many(&["key2".to_owned(), "missing".to_owned()])?;
+    store.remove("key1".to_owned())?;
+    assert!(!store.remove_if_exists("key1".to_owned())?);
+    store.compact()?;
+
+    // Clones share the counters
+    store.clone().get("key3".to_owned())?;
+    assert_eq!(
+        store.metrics(),
+        Metrics {
+            gets: 5,
+            sets: 3,
+            removes: 1,
+            compactions: 1,
+            cache_hits: 3,
+            misses: 2,
+            value_cache_hits: 0,
+            bloom_filter_skips: 0,
+        }
+    );
+
+    Ok(())
+}
+
+// The bloom filter should never rule out a live key, before or after reopening, and
+// should rule out most absent ones
+#[test]
+fn bloom_filter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().bloom_filter(20000);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    let keys: Vec<String> = (0..20000).map(|id| format!("key{}", id)).collect();
+    for key in &keys[..10000] {
+        store.set(key.clone(), "value".to_owned())?;
+    }
+    store.set_many(
+        keys[10000..]
+            .iter()
+            .map(|key| (key.clone(), "value".to_owned()))
+            .collect(),
+    )?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        for key in &keys {
+            assert!(store.contains_key(key), "{} should be present", key);
+            assert_eq!(store.get(key.clone())?, Some("value".to_owned()));
+        }
+        Ok(())
+    };
+    check(&store)?;
+    assert_eq!(store.metrics().bloom_filter_skips, 0);
+
+    for id in 0..1000 {
+        assert_eq!(store.get(format!("missing{}", id))?, None);
+    }
+    assert!(store.metrics().bloom_filter_skips > 900);
+
+    // Removed keys are absent whatever the filter says, and compaction drops them from it
+    store.remove("key0".to_owned())?;
+    assert!(!store.contains_key("key0"));
+    store.compact()?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    store.set("key0".to_owned(), "value".to_owned())?;
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    check(&store)?;
+
+    Ok(())
+}
+
+// Repeated gets should be served from the value cache until the key is written again
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().value_cache_capacity(2);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.metrics().value_cache_hits, 0);
+    assert_eq!(store.get_shared("key1")?.as_deref(), Some("value1"));
+    assert_eq!(store.metrics().value_cache_hits, 1);
+
+    // A set in between invalidates the cached value, as does a remove
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.metrics().value_cache_hits, 1);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.metrics().value_cache_hits, 2);
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.metrics().value_cache_hits, 2);
+
+    // The least recently used key is evicted once the cache is full
+    for key in ["key2", "key3", "key4"] {
+        store.set(key.to_owned(), "value".to_owned())?;
+    }
+    for key in ["key2", "key3", "key2", "key4", "key2", "key3"] {
+        assert_eq!(store.get(key.to_owned())?, Some("value".to_owned()));
+    }
+    // Only key2 hits: key4 evicts key3, which then evicts key4
+    assert_eq!(store.metrics().value_cache_hits, 4);
+
+    Ok(())
+}
+
+// A batch read should return each key's value in the order asked for
+#[test]
+fn get_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..50 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    // Rewrite some keys so the log order differs from key order
+    for key_id in (0..50).step_by(7) {
+        store.set(format!("key{}", key_id), format!("new{}", key_id))?;
+    }
+    store.set_with_ttl("expired".to_owned(), "value".to_owned(), Duration::ZERO)?;
+
+    let keys: Vec<String> = [
+        "key42", "key7", "missing", "key3", "expired", "key42", "key0",
+    ]
+    .iter()
+    .map(|key| key.to_string())
+    .collect();
+    assert_eq!(
+        store.get_many(&keys)?,
+        vec![
+            Some("new42".to_owned()),
+            Some("new7".to_owned()),
+            None,
+            Some("value3".to_owned()),
+            None,
+            Some("new42".to_owned()),
+            Some("new0".to_owned()),
+        ]
+    );
+    assert!(!store.contains_key("expired"));
+    assert!(store.get_many(&[])?.is_empty());
+
+    Ok(())
+}
+
+// Tiny and large log buffers should both round-trip values longer than the buffer
+#[test]
+fn buffer_capacity() -> Result<()> {
+    for capacity in [16, 256 * 1024] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::default().buffer_capacity(capacity);
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), "v".repeat(key_id))?;
+        }
+        drop(store);
+
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        for key_id in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some("v".repeat(key_id))
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// A store seeded from an iterator should hold every pair, also after a reopen
+#[test]
+fn open_from_iter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let pairs: Vec<(String, String)> = (0..1000)
+        .map(|id| (format!("key{}", id), format!("value{}", id)))
+        .collect();
+    let store = KvStore::open_from_iter(temp_dir.path(), pairs.clone())?;
+    assert_eq!(store.len(), 1000);
+    for (key, value) in &pairs {
+        assert_eq!(store.get(key.clone())?.as_ref(), Some(value));
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.entries()?.len(), 1000);
+
+    Ok(())
+}
+
+// Replaying a remove of a key that was never set should be counted in the report
+#[test]
+fn open_with_report() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let set = |key: &str| Command::Set {
+        key: key.to_owned(),
+        value: "value".to_owned(),
+    };
+    let rm = |key: &str| Command::Rm {
+        key: key.to_owned(),
+    };
+    let mut log = Vec::new();
+    for command in [
+        set("key1"),
+        set("key2"),
+        rm("key1"),
+        rm("key1"),
+        rm("never-set"),
+    ] {
+        log.extend(JsonCodec.encode(&command)?);
+    }
+    fs::write(temp_dir.path().join("1.log"), log)?;
+
+    // Compacting would drop the removes from the log
+    let options = KvStoreOptions::default().compaction_strategy(CompactionStrategy::Manual);
+    let (store, report) = KvStore::open_with_report(temp_dir.path(), options.clone())?;
+    assert_eq!(report.dangling_removes, 2);
+    assert_eq!(store.keys().collect::<Vec<_>>(), vec!["key2"]);
+
+    store.remove("key2".to_owned())?;
+    drop(store);
+    let (_, report) = KvStore::open_with_report(temp_dir.path(), options)?;
+    assert_eq!(
+        report,
+        OpenReport {
+            dangling_removes: 2
+        }
+    );
+
+    Ok(())
+}
+
+// Reindex should pick up records written to the log behind the store's back
+#[test]
+fn reindex() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..3 {
+        store.set(format!("key{}", iter), format!("value{}", iter))?;
+    }
+
+    let mut log = OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join("1.log"))?;
+    log.write_all(&JsonCodec.encode(&Command::Set {
+        key: "key3".to_owned(),
+        value: "value3".to_owned(),
+    })?)?;
+    log.write_all(&JsonCodec.encode(&Command::Rm {
+        key: "key0".to_owned(),
+    })?)?;
+    drop(log);
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+
+    store.reindex()?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.len(), 3);
+
+    // Writes after the reindex land after the new records
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.get("key0".to_owned())?, None);
+
+    Ok(())
+}
+
+// A flipped byte should fail the record's checksum, both on open and on get
+#[test]
+fn checksum_mismatch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..3 {
+        store.set(format!("key{}", iter), format!("value{}", iter))?;
+    }
+
+    let log = temp_dir.path().join("1.log");
+    let record_length = file_size(&log) / 3;
+    let mut bytes = fs::read(&log)?;
+    let value = bytes
+        .windows(6)
+        .position(|window| window == b"value1")
+        .expect("value should be in the log");
+    bytes[value] = b'V';
+    fs::write(&log, bytes)?;
+
+    match store.get("key1".to_owned()) {
+        Err(KvStoreError::ChecksumMismatch { offset }) => assert_eq!(offset, record_length),
+        _ => panic!("expected a checksum mismatch"),
+    }
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    drop(store);
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvStoreError::ChecksumMismatch { offset }) => assert_eq!(offset, record_length),
+        _ => panic!("expected a checksum mismatch"),
+    }
+
+    Ok(())
+}
+
+// Stats should follow the live keys and the bytes left behind by overwrites, across a
+// reopen too
+#[test]
+fn stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .compaction_threshold(1 << 20)
+        .compaction_ratio(1.0);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    assert_eq!(
+        store.stats()?,
+        Stats {
+            live_keys: 0,
+            dirt_bytes: 0,
+            log_size_bytes: 0,
+            compaction_threshold: 1 << 20,
+        }
+    );
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let record_length = store.stats()?.log_size_bytes / 2;
+    assert_eq!(store.stats()?.live_keys, 2);
+    assert_eq!(store.stats()?.dirt_bytes, 0);
+
+    // Each overwrite leaves the previous record behind as dirt
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.set("key1".to_owned(), "value4".to_owned())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.live_keys, 2);
+    assert_eq!(stats.dirt_bytes, 2 * record_length);
+    assert_eq!(stats.log_size_bytes, 4 * record_length);
+
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.stats()?.live_keys, 1);
+    assert_eq!(
+        store.stats()?.log_size_bytes,
+        file_size(&temp_dir.path().join("1.log"))
+    );
+
+    let stats = store.stats()?;
+    assert!(stats.dirt_bytes > 2 * record_length);
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.stats()?, stats);
+
+    Ok(())
+}
+
+// Entries set with a TTL should read as missing once it has passed, even after reopening
+#[test]
+fn ttl_expiry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let changes = store.watch("short".to_owned())?;
+
+    store.set_with_ttl(
+        "short".to_owned(),
+        "value1".to_owned(),
+        Duration::from_millis(50),
+    )?;
+    store.set_with_ttl(
+        "long".to_owned(),
+        "value2".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    assert_eq!(store.get("short".to_owned())?, Some("value1".to_owned()));
+
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(store.get("short".to_owned())?, None);
+    assert!(!store.contains_key("short"));
+    assert_eq!(store.get("long".to_owned())?, Some("value2".to_owned()));
+    // A read finding the key expired tells its watchers, once
+    assert_eq!(store.get("short".to_owned())?, None);
+    assert_eq!(
+        changes.try_iter().collect::<Vec<_>>(),
+        vec![Some("value1".to_owned()), None]
+    );
+
+    store.set_with_ttl("swapped".to_owned(), "value5".to_owned(), Duration::ZERO)?;
+    assert_eq!(
+        store.set_and_get("swapped".to_owned(), "value6".to_owned())?,
+        None
+    );
+
+    // A plain set after expiry should make the key live again
+    store.set("short".to_owned(), "value3".to_owned())?;
+    store.set_with_ttl("gone".to_owned(), "value4".to_owned(), Duration::ZERO)?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("gone".to_owned())?, None);
+    assert!(!store.contains_key("gone"));
+    assert_eq!(store.get("short".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("long".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Overwriting a small dataset should compact once most of the log is stale, while
+// writing distinct keys never should
+#[test]
+fn compaction_ratio() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().compaction_ratio(0.6);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for iter in 0..1000 {
+        store.set(format!("key{}", iter), format!("value{}", iter))?;
+    }
+    assert_eq!(
+        log_files(temp_dir.path()),
+        vec![temp_dir.path().join("1.log")]
+    );
+    assert_eq!(store.stats()?.dirt_bytes, 0);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let mut max_size = 0;
+    for iter in 0..1000 {
+        store.set("key".to_owned(), format!("value{}", iter))?;
+        max_size = max_size.max(store.stats()?.log_size_bytes);
+    }
+    assert!(!temp_dir.path().join("1.log").exists());
+    // The log never grows much past a few copies of the one live record
+    assert!(max_size < 10 * store.stats()?.log_size_bytes);
+    assert_eq!(store.get("key".to_owned())?, Some("value999".to_owned()));
+
+    Ok(())
+}
+
+// Overwriting one key should compact only under the strategies that fire on stale bytes
+#[test]
+fn compaction_strategy() -> Result<()> {
+    for (strategy, compacts) in [
+        (CompactionStrategy::Never, false),
+        (CompactionStrategy::OnThreshold { bytes: 4096 }, true),
+        (CompactionStrategy::OnThreshold { bytes: 1 << 20 }, false),
+        (CompactionStrategy::OnRatio { ratio: 0.6 }, true),
+        (CompactionStrategy::Manual, false),
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::default().compaction_strategy(strategy);
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        for iter in 0..1000 {
+            store.set("key".to_owned(), format!("value{}", iter))?;
+        }
+        assert_eq!(
+            !temp_dir.path().join("1.log").exists(),
+            compacts,
+            "{:?}",
+            strategy
+        );
+        assert_eq!(store.get("key".to_owned())?, Some("value999".to_owned()));
+    }
+
+    Ok(())
+}
+
+// A manual store should compact on request, while an append-only one never does
+#[test]
+fn manual_and_never_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().compaction_strategy(CompactionStrategy::Manual);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for iter in 0..1000 {
+        store.set("key".to_owned(), format!("value{}", iter))?;
+    }
+    assert!(store.compact()? > 0);
+    assert!(!temp_dir.path().join("1.log").exists());
+    assert_eq!(store.stats()?.dirt_bytes, 0);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .compaction_strategy(CompactionStrategy::Never)
+        .compact_on_open(true);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for iter in 0..1000 {
+        store.set("key".to_owned(), format!("value{}", iter))?;
+    }
+    let stats = store.stats()?;
+    assert_eq!(store.compact()?, 0);
+    assert_eq!(store.stats()?, stats);
+    drop(store);
+
+    // Nor does reopening with `compact_on_open`
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert!(temp_dir.path().join("1.log").exists());
+    assert_eq!(store.stats()?.log_size_bytes, stats.log_size_bytes);
+    assert_eq!(store.get("key".to_owned())?, Some("value999".to_owned()));
+
+    Ok(())
+}
+
+// Removing keys should count both their records and the removals as dirt
+#[test]
+fn remove_counts_dirt() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .compaction_threshold(1 << 20)
+        .compaction_ratio(1.0);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for iter in 0..100 {
+        store.set(format!("key{}", iter), format!("value{}", iter))?;
+    }
+    let set_bytes = store.stats()?.log_size_bytes;
+
+    for iter in 0..100 {
+        store.remove(format!("key{}", iter))?;
+    }
+    let stats = store.stats()?;
+    assert_eq!(stats.live_keys, 0);
+    // Nothing in the log is live any more
+    assert_eq!(stats.dirt_bytes, stats.log_size_bytes);
+    assert!(stats.dirt_bytes > set_bytes);
+
+    Ok(())
+}
+
+// Every position should cover exactly one record, whether records end in a newline
+// (JSON) or follow each other with no separator at all (bincode)
+#[test]
+fn record_boundaries() -> Result<()> {
+    record_boundaries_with(JsonCodec)?;
+    record_boundaries_with(BincodeCodec)
+}
+
+fn record_boundaries_with(codec: impl LogCodec + Copy + 'static) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .codec(codec)
+        .compaction_strategy(CompactionStrategy::Manual);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    let pairs = [
+        ("key1", ""),
+        ("key2", " "),
+        ("key3", "\n"),
+        ("key4", "  value with  spaces\t"),
+        ("key5", "\r\n\r\n"),
+    ];
+
+    let mut end = 0;
+    for (key, value) in pairs {
+        let position = store
+            .set_with_position(key.to_owned(), value.to_owned())?
+            .expect("KvStore reports positions");
+        // Records follow each other with nothing in between
+        assert_eq!(position.start, end);
+        end = position.start + position.length;
+
+        let log = fs::read(temp_dir.path().join("1.log")).expect("unable to read log file");
+        assert_eq!(log.len() as u64, end);
+        let record = &log[position.start as usize..end as usize];
+        assert_eq!(
+            codec.decode(record, position.start)?,
+            Command::Set {
+                key: key.to_owned(),
+                value: value.to_owned(),
+            }
+        );
+    }
+
+    // Replay has to find the same boundaries
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert!(store.verify()?.is_ok());
+    for (key, value) in pairs {
+        assert_eq!(store.get(key.to_owned())?, Some(value.to_owned()));
+    }
+
+    Ok(())
+}
+
+// Values built from JSON fragments, quotes, escapes and newlines should read back
+// exactly, before and after reopening and compacting
+#[test]
+fn json_like_values() -> Result<()> {
+    json_like_values_with(JsonCodec)?;
+    json_like_values_with(BincodeCodec)
+}
+
+fn json_like_values_with(codec: impl LogCodec + 'static) -> Result<()> {
+    const FRAGMENTS: &[&str] = &[
+        "{\"a\":1}",
+        "{",
+        "}",
+        "[",
+        "]",
+        "\"",
+        "\\",
+        "\\\"",
+        "\n",
+        "\r\n",
+        " ",
+        ",",
+        ":",
+        " 0badc0de\n",
+        "{\"Set\":{\"key\":\"k\",\"value\":\"v\"}}",
+        "\u{0}",
+        "é",
+        "🦀",
+    ];
+    let mut rng = StdRng::seed_from_u64(90);
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .codec(codec)
+        .compaction_strategy(CompactionStrategy::Manual);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    let mut expected = BTreeMap::new();
+    for iter in 0..500 {
+        let key = format!(
+            "key{}{}",
+            iter % 100,
+            FRAGMENTS[rng.gen_range(0, FRAGMENTS.len())]
+        );
+        let value: String = (0..rng.gen_range(0, 20))
+            .map(|_| FRAGMENTS[rng.gen_range(0, FRAGMENTS.len())])
+            .collect();
+        store.set(key.clone(), value.clone())?;
+        assert_eq!(store.get(key.clone())?.as_ref(), Some(&value));
+        expected.insert(key, value);
+    }
+
+    let check = |store: &KvStore| -> Result<()> {
+        for (key, value) in &expected {
+            assert_eq!(store.get(key.clone())?.as_ref(), Some(value));
+        }
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    check(&store)?;
+    store.compact()?;
+    check(&store)
+}
+
+// The bincode codec should round-trip every command through reopen and compaction, in
+// less space than JSON
+#[test]
+fn bincode_codec() -> Result<()> {
+    let json_dir = TempDir::new().expect("unable to create temporary working directory");
+    let bincode_dir = TempDir::new().expect("unable to create temporary working directory");
+    let json_options = KvStoreOptions::default().codec(JsonCodec);
+    let bincode_options = KvStoreOptions::default().codec(BincodeCodec);
+
+    for (dir, options) in [
+        (&json_dir, json_options.clone()),
+        (&bincode_dir, bincode_options.clone()),
+    ] {
+        let store = KvStore::open_with_options(dir.path(), options)?;
+        for iter in 0..1000 {
+            store.set(format!("key{}", iter), format!("value{}", iter))?;
+        }
+        store.set_bytes("bytes".to_owned(), vec![0, b'\n', 255])?;
+        store.remove("key0".to_owned())?;
+    }
+
+    let json_size = file_size(&json_dir.path().join("1.log"));
+    let bincode_size = file_size(&bincode_dir.path().join("1.log"));
+    assert!(
+        bincode_size < json_size,
+        "bincode log is {} bytes, JSON log is {} bytes",
+        bincode_size,
+        json_size
+    );
+
+    let store = KvStore::open_with_options(bincode_dir.path(), bincode_options.clone())?;
+    assert_eq!(store.len(), 1000);
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key999".to_owned())?, Some("value999".to_owned()));
+    assert_eq!(
+        store.get_bytes("bytes".to_owned())?,
+        Some(vec![0, b'\n', 255])
+    );
+
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open_with_options(bincode_dir.path(), bincode_options)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Compressed values should take far less disk and read back unchanged
+#[test]
+fn compress_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().compress_values(true);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    let large = "abcdefgh".repeat(128 * 1024);
+    store.set("large".to_owned(), large.clone())?;
+    store.set("small".to_owned(), "value1".to_owned())?;
+    assert!(file_size(&temp_dir.path().join("1.log")) < large.len() as u64 / 100);
+    assert_eq!(store.get("large".to_owned())?, Some(large.clone()));
+    assert_eq!(store.get("small".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(
+        store.get_bytes("small".to_owned())?,
+        Some(b"value1".to_vec())
+    );
+    drop(store);
+
+    // Compressed values remain readable with compression turned off
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("large".to_owned())?, Some(large));
+    assert_eq!(store.get("small".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// An export should restore the same pairs after clearing the store
+#[test]
+fn export_import() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("b".to_owned(), "value2".to_owned())?;
+    store.set("a".to_owned(), "value1".to_owned())?;
+    store.set("c".to_owned(), "quote \" and newline \n".to_owned())?;
+    store.remove("b".to_owned())?;
+    let original = store.range(..)?;
+
+    let mut snapshot = Vec::new();
+    store.export(&mut snapshot)?;
+    assert_eq!(
+        String::from_utf8(snapshot.clone()).unwrap(),
+        r#"{"a":"value1","c":"quote \" and newline \n"}"#
+    );
+
+    store.clear()?;
+    assert!(store.is_empty());
+    assert_eq!(store.import(&snapshot[..])?, 2);
+    assert_eq!(store.range(..)?, original);
+
+    Ok(())
+}
+
+// The default should only be computed when the key is missing
+#[test]
+fn get_or_insert_with() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let mut calls = 0;
+
+    let value = store.get_or_insert_with("key1".to_owned(), || {
+        calls += 1;
+        "value1".to_owned()
+    })?;
+    assert_eq!(value, "value1");
+    assert_eq!(calls, 1);
+
+    let value = store.get_or_insert_with("key1".to_owned(), || {
+        calls += 1;
+        "value2".to_owned()
+    })?;
+    assert_eq!(value, "value1");
+    assert_eq!(calls, 1);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// A swap should only happen when the current value is the expected one
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    // Create if absent
+    assert!(store.compare_and_swap("key1".to_owned(), None, "value1".to_owned())?);
+    assert!(!store.compare_and_swap("key1".to_owned(), None, "value2".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // Mismatch
+    assert!(!store.compare_and_swap(
+        "key1".to_owned(),
+        Some("value0".to_owned()),
+        "value2".to_owned()
+    )?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // Match
+    assert!(store.compare_and_swap(
+        "key1".to_owned(),
+        Some("value1".to_owned()),
+        "value2".to_owned()
+    )?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert!(!store.compare_and_swap(
+        "key2".to_owned(),
+        Some("value1".to_owned()),
+        "value2".to_owned()
+    )?);
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Increments should start from 0, accept negative deltas and refuse non-integers
+#[test]
+fn incr() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.incr("counter".to_owned(), 5)?, 5);
+    assert_eq!(store.incr("counter".to_owned(), -8)?, -3);
+    assert_eq!(store.get("counter".to_owned())?, Some("-3".to_owned()));
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    match store.incr("key1".to_owned(), 1) {
+        Err(KvStoreError::NotAnInteger(value)) => assert_eq!(value, "value1"),
+        _ => panic!("expected a not-an-integer error"),
+    }
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // Open from disk again and check the counter persisted
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.incr("counter".to_owned(), 3)?, 0);
+
+    Ok(())
+}
+
+// A directory should only be open in one store at a time
+#[test]
+fn open_locks_directory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let clone = store.clone();
+
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvStoreError::AlreadyLocked)
+    ));
+
+    // The lock is held until the last clone is dropped
+    drop(store);
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvStoreError::AlreadyLocked)
+    ));
+    drop(clone);
+    KvStore::open(temp_dir.path())?;
+
+    Ok(())
+}
+
+// A read-only store should serve reads alongside a writer and refuse writes
+#[test]
+fn open_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(KvStore::open_read_only(temp_dir.path().join("missing")).is_err());
+    assert!(KvStore::open_read_only(temp_dir.path()).is_err());
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(reader.keys().collect::<Vec<_>>(), vec!["key1", "key2"]);
+    assert_eq!(
+        reader.range(..)?,
+        pairs(&[("key1", "value1"), ("key2", "value2")])
+    );
+
+    assert!(matches!(
+        reader.set("key3".to_owned(), "value3".to_owned()),
+        Err(KvStoreError::ReadOnly)
+    ));
+    assert!(matches!(
+        reader.remove("key1".to_owned()),
+        Err(KvStoreError::ReadOnly)
+    ));
+    assert!(matches!(reader.compact(), Err(KvStoreError::ReadOnly)));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// A log file going missing under the store should fail reads instead of panicking
+#[test]
+fn get_missing_log_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    // A fresh clone opens its read handles lazily, after the file is gone
+    let clone = store.clone();
+    for log_file in log_files(temp_dir.path()) {
+        fs::remove_file(log_file).expect("unable to remove log file");
+    }
+    assert!(clone.get("key1".to_owned()).is_err());
+
+    Ok(())
+}
+
+// An IO error should show the OS message and keep it as its source
+#[test]
+fn io_error_display() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let clone = store.clone();
+    let mut os_message = String::new();
+    for log_file in log_files(temp_dir.path()) {
+        fs::remove_file(&log_file).expect("unable to remove log file");
+        os_message = fs::File::open(&log_file).unwrap_err().to_string();
+    }
+    let err = clone.get("key1".to_owned()).unwrap_err();
+    assert!(matches!(err, KvStoreError::IoError(_)));
+    assert_eq!(err.to_string(), format!("IO error: {}", os_message));
+    let source = std::error::Error::source(&err).expect("IO errors should have a source");
+    assert!(source.downcast_ref::<std::io::Error>().is_some());
+
+    Ok(())
+}
+
+// Many reads should reuse the same file handles
+#[cfg(target_os = "linux")]
+#[test]
+fn sequential_gets_reuse_handles() -> Result<()> {
+    // Counts this process's open file descriptors onto files inside `dir`
+    fn open_handles(dir: &Path) -> usize {
+        fs::read_dir("/proc/self/fd")
+            .expect("unable to list file descriptors")
+            .filter_map(|entry| fs::read_link(entry.ok()?.path()).ok())
+            .filter(|target| target.starts_with(dir))
+            .count()
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path().canonicalize()?;
+    let store = KvStore::open(&dir)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    let handles = open_handles(&dir);
+
+    for iter in 0..1000 {
+        let key_id = iter * 7 % 100;
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    assert_eq!(open_handles(&dir), handles);
+
+    Ok(())
+}
+
+// Stores with different log file names should share a directory without interfering
+#[test]
+fn log_file_name() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = |name: &str| {
+        let options = KvStoreOptions::default()
+            .log_file_name(name)
+            .compaction_threshold(200);
+        KvStore::open_with_options(temp_dir.path(), options)
+    };
+    let users = open("users")?;
+    let items = open("items")?;
+    let default = KvStore::open(temp_dir.path())?;
+
+    for iter in 0..20 {
+        users.set("key1".to_owned(), format!("user{}", iter))?;
+        items.set("key1".to_owned(), format!("item{}", iter))?;
+    }
+    items.set("key2".to_owned(), "item".to_owned())?;
+    default.set("key3".to_owned(), "default".to_owned())?;
+    users.compact()?;
+    assert!(temp_dir.path().join("users.LOCK").exists());
+
+    drop((users, items, default));
+    let users = open("users")?;
+    let items = open("items")?;
+    let default = KvStore::open(temp_dir.path())?;
+    assert_eq!(users.get("key1".to_owned())?, Some("user19".to_owned()));
+    assert_eq!(users.get("key2".to_owned())?, None);
+    assert_eq!(items.get("key1".to_owned())?, Some("item19".to_owned()));
+    assert_eq!(items.get("key2".to_owned())?, Some("item".to_owned()));
+    assert_eq!(default.keys().collect::<Vec<_>>(), vec!["key3"]);
+
+    Ok(())
+}
+
+// Positions should follow every write, read and seek
+#[test]
+fn buffered_positions() -> Result<()> {
+    let mut writer = BufWriterWithPos::new(Cursor::new(Vec::new()));
+    writer.write_all(b"first line\n")?;
+    assert_eq!(writer.position(), 11);
+    writer.write_all(b"second line\n")?;
+    assert_eq!(writer.position(), 23);
+    writer.seek(SeekFrom::Start(6))?;
+    assert_eq!(writer.position(), 6);
+    writer.write_all(b"LINE")?;
+    assert_eq!(writer.position(), 10);
+    assert_eq!(writer.seek(SeekFrom::End(0))?, 23);
+    writer.write_all(b"third\n")?;
+    assert_eq!(writer.position(), 29);
+    writer.flush()?;
+
+    let log = b"first LINE\nsecond line\nthird\n".to_vec();
+    let mut reader = BufReaderWithPos::new(Cursor::new(log));
+    let mut line = Vec::new();
+    reader.read_until(b'\n', &mut line)?;
+    assert_eq!(reader.position(), 11);
+    let mut word = [0; 6];
+    reader.read_exact(&mut word)?;
+    assert_eq!(&word, b"second");
+    assert_eq!(reader.position(), 17);
+
+    // Seeks back into the buffer and past it
+    reader.seek(SeekFrom::Start(6))?;
+    assert_eq!(reader.position(), 6);
+    reader.read_exact(&mut word[..4])?;
+    assert_eq!(&word[..4], b"LINE");
+    assert_eq!(reader.position(), 10);
+    reader.seek(SeekFrom::End(-6))?;
+    assert_eq!(reader.position(), 23);
+    line.clear();
+    reader.read_until(b'\n', &mut line)?;
+    assert_eq!(line, b"third\n");
+    assert_eq!(reader.position(), 29);
+
+    Ok(())
+}
+
+fn engine_round_trip(engine: &impl KvsEngine) -> Result<()> {
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    engine.remove("key1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert!(engine.remove("key1".to_owned()).is_err());
+
+    assert!(engine.compare_and_swap("key1".to_owned(), None, "value1".to_owned())?);
+    assert!(!engine.compare_and_swap("key1".to_owned(), None, "value2".to_owned())?);
+    assert!(engine.compare_and_swap(
+        "key1".to_owned(),
+        Some("value1".to_owned()),
+        "value2".to_owned()
+    )?);
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    assert_eq!(engine.incr("key2".to_owned(), 2)?, 2);
+    assert_eq!(engine.incr("key2".to_owned(), -5)?, -3);
+    assert!(matches!(
+        engine.incr("key1".to_owned(), 1),
+        Err(KvStoreError::NotAnInteger(_))
+    ));
+
+    engine.set("key10".to_owned(), "value10".to_owned())?;
+    assert_eq!(
+        engine.prefix_scan("key1")?,
+        vec![
+            ("key1".to_owned(), "value2".to_owned()),
+            ("key10".to_owned(), "value10".to_owned()),
+        ]
+    );
+    assert_eq!(
+        engine.range((
+            Bound::Excluded("key1".to_owned()),
+            Bound::Included("key2".to_owned())
+        ))?,
+        vec![
+            ("key10".to_owned(), "value10".to_owned()),
+            ("key2".to_owned(), "-3".to_owned()),
+        ]
+    );
+    assert!(engine
+        .range((
+            Bound::Included("key2".to_owned()),
+            Bound::Excluded("key1".to_owned())
+        ))?
+        .is_empty());
+
+    assert_eq!(engine.remove_prefix("key1")?, 2);
+    assert_eq!(engine.remove_prefix("key1")?, 0);
+    assert_eq!(
+        engine.prefix_scan("key")?,
+        vec![("key2".to_owned(), "-3".to_owned())]
+    );
+
+    let changes = engine.watch("watched".to_owned())?;
+    engine.set("watched".to_owned(), "value1".to_owned())?;
+    engine.set("other".to_owned(), "value1".to_owned())?;
+    assert!(engine.compare_and_swap(
+        "watched".to_owned(),
+        Some("value1".to_owned()),
+        "value2".to_owned()
+    )?);
+    engine.remove("watched".to_owned())?;
+    assert_eq!(engine.incr("watched".to_owned(), 1)?, 1);
+    assert_eq!(
+        changes.try_iter().collect::<Vec<_>>(),
+        vec![
+            Some("value1".to_owned()),
+            Some("value2".to_owned()),
+            None,
+            Some("1".to_owned()),
+        ]
+    );
+
+    engine.flush_durable()?;
+    Ok(())
+}
+
+// Both engines should be usable through the `KvsEngine` trait
+#[test]
+fn engines_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    engine_round_trip(&KvStore::open(temp_dir.path())?)?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    engine_round_trip(&SledKvsEngine::open(temp_dir.path())?)?;
+
+    engine_round_trip(&MemoryKvsEngine::new())?;
+
+    Ok(())
+}
+
+// Values written through sled should persist across reopen
+#[test]
+fn sled_persists_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+
+    drop(engine);
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
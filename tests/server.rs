@@ -0,0 +1,1499 @@
+use assert_cmd::cargo::CommandCargoExt;
+use kvs::{
+    read_frame, write_frame, Command, ErrorKind, KvStore, KvStoreError, KvsClient, KvsEngine,
+    KvsServer, ReconnectingClient, Response, Result, SharedQueueThreadPool, ThreadPool,
+    MAX_FRAME_LEN,
+};
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// Reopening a data directory with a different engine should be refused
+#[test]
+fn engine_mismatch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(None, Some("kvs".to_owned()), temp_dir.path(), None)?;
+    drop(server);
+
+    match KvsServer::new(None, Some("sled".to_owned()), temp_dir.path(), None) {
+        Err(KvStoreError::EngineMismatch {
+            persisted,
+            requested,
+        }) => {
+            assert_eq!(persisted, "kvs");
+            assert_eq!(requested, "sled");
+        }
+        _ => panic!("expected an engine mismatch"),
+    }
+
+    // The original engine can still be reopened
+    KvsServer::new(None, Some("kvs".to_owned()), temp_dir.path(), None)?;
+
+    Ok(())
+}
+
+// Bad configuration should come back as an error instead of ending the process
+#[test]
+fn invalid_configuration_returns_errors() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    match KvsServer::new(Some("not-an-addr".to_owned()), None, temp_dir.path(), None) {
+        Err(KvStoreError::InvalidAddress(addr)) => assert_eq!(addr, "not-an-addr"),
+        _ => panic!("expected an invalid address error"),
+    }
+    match KvsServer::new(None, Some("redis".to_owned()), temp_dir.path(), None) {
+        Err(KvStoreError::InvalidEngine(engine)) => assert_eq!(engine, "redis"),
+        _ => panic!("expected an invalid engine error"),
+    }
+    match KvsClient::new(Some("not-an-addr".to_owned())) {
+        Err(KvStoreError::InvalidAddress(addr)) => assert_eq!(addr, "not-an-addr"),
+        _ => panic!("expected an invalid address error"),
+    }
+}
+
+// A config file should supply the address, engine, data directory and threshold
+#[test]
+fn server_config_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_dir = temp_dir.path().join("data");
+    fs::create_dir(&data_dir)?;
+    let config = temp_dir.path().join("kvs.toml");
+    fs::write(
+        &config,
+        format!(
+            "addr = \"127.0.0.1:4025\"\nengine = \"kvs\"\ndata_dir = {:?}\ncompaction_threshold = 1024\ntcp_nodelay = false\n",
+            data_dir
+        ),
+    )?;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let server_stop = stop.clone();
+    let mut server = KvsServer::new(None, None, "", Some(config.clone()))?;
+    let handle = thread::spawn(move || server.run_until(server_stop));
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::new(Some("127.0.0.1:4025".to_owned()))?;
+    assert!(matches!(
+        client.send(Command::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+        })?,
+        Response::SetOk(_)
+    ));
+    drop(client);
+    stop_server((stop, handle))?;
+    assert!(data_dir.join("1.log").exists());
+
+    // Flags override the file
+    match KvsServer::new(
+        Some("not-an-addr".to_owned()),
+        None,
+        "",
+        Some(config.clone()),
+    ) {
+        Err(KvStoreError::InvalidAddress(addr)) => assert_eq!(addr, "not-an-addr"),
+        _ => panic!("expected an invalid address error"),
+    }
+
+    fs::write(&config, "port = 4000\n")?;
+    assert!(matches!(
+        KvsServer::new(None, None, "", Some(config)),
+        Err(KvStoreError::InvalidConfig(_))
+    ));
+
+    Ok(())
+}
+
+/// Runs a kvs server on `addr` in a background thread until the returned flag is set.
+fn start_server(addr: &'static str, path: &Path) -> (Arc<AtomicBool>, JoinHandle<Result<()>>) {
+    let stop = Arc::new(AtomicBool::new(false));
+    let server_stop = stop.clone();
+    let path = path.to_owned();
+    let handle = thread::spawn(move || {
+        let mut server = KvsServer::new(Some(addr.to_owned()), None, path, None)?;
+        server.run_until(server_stop)
+    });
+    thread::sleep(Duration::from_secs(1));
+    (stop, handle)
+}
+
+fn stop_server((stop, handle): (Arc<AtomicBool>, JoinHandle<Result<()>>)) -> Result<()> {
+    stop.store(true, Ordering::SeqCst);
+    handle.join().expect("server thread panicked")
+}
+
+// Setting the stop flag should make `run_until` return
+#[test]
+fn graceful_shutdown() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4010";
+    let server = start_server(addr, temp_dir.path());
+
+    let mut client = KvsClient::new(Some(addr.to_owned()))?;
+    client.send(Command::Set {
+        key: "key1".to_owned(),
+        value: "value1".to_owned(),
+    })?;
+
+    drop(client);
+    stop_server(server)?;
+
+    // The connection's thread releases the store once it sees the client has gone
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let store = loop {
+        match KvStore::open(temp_dir.path()) {
+            Err(KvStoreError::AlreadyLocked) if Instant::now() < deadline => {
+                thread::sleep(Duration::from_millis(10));
+            }
+            result => break result?,
+        }
+    };
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Concurrent clients should all be served with their own results
+#[test]
+fn concurrent_clients() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4011";
+    let server = start_server(addr, temp_dir.path());
+
+    let clients: Vec<_> = (0..8)
+        .map(|id| {
+            thread::spawn(move || -> Result<()> {
+                let key = format!("key{}", id);
+                let value = format!("value{}", id);
+                let mut client = KvsClient::new(Some(addr.to_owned()))?;
+                let response = client.send(Command::Set {
+                    key: key.clone(),
+                    value: value.clone(),
+                })?;
+                assert!(matches!(response, Response::SetOk(_)));
+
+                match client.send(Command::Get { key })? {
+                    Response::GetOk(got) => assert_eq!(got, value),
+                    response => panic!("unexpected response {:?}", response),
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for client in clients {
+        client.join().expect("client thread panicked")?;
+    }
+
+    stop_server(server)
+}
+
+#[test]
+fn thread_pool_runs_jobs() -> Result<()> {
+    let (sender, receiver) = mpsc::channel();
+    let pool = SharedQueueThreadPool::new(4)?;
+
+    for id in 0..100 {
+        let sender = sender.clone();
+        pool.spawn(move || sender.send(id).unwrap());
+    }
+    // A panicking job must not take the pool down
+    pool.spawn(|| panic!("job panicked"));
+    let sender_clone = sender.clone();
+    pool.spawn(move || sender_clone.send(100).unwrap());
+    drop(sender);
+
+    let mut received: Vec<i32> = receiver.iter().take(101).collect();
+    received.sort_unstable();
+    assert_eq!(received, (0..=100).collect::<Vec<_>>());
+
+    Ok(())
+}
+
+// Clients hanging up before or partway through a command shouldn't stop the server
+#[test]
+fn client_disconnect_mid_request() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4039";
+    let server = start_server(addr, temp_dir.path());
+
+    drop(TcpStream::connect(addr)?);
+    // Part of the length prefix
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(&[0, 0])?;
+    drop(stream);
+    // The length prefix, but only part of the payload
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(&100u32.to_be_bytes())?;
+    stream.write_all(&[1, 2, 3])?;
+    drop(stream);
+    thread::sleep(Duration::from_millis(100));
+
+    let mut client = KvsClient::new(Some(addr.to_owned()))?;
+    assert!(matches!(
+        client.send(Command::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+        })?,
+        Response::SetOk(_)
+    ));
+    match client.send(Command::Get {
+        key: "key1".to_owned(),
+    })? {
+        Response::GetOk(value) => assert_eq!(value, "value1"),
+        response => panic!("unexpected response {:?}", response),
+    }
+    drop(client);
+
+    stop_server(server)
+}
+
+// A length prefix over the frame limit should only cost its own connection
+#[test]
+fn oversized_frame_is_rejected() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4042";
+    let server = start_server(addr, temp_dir.path());
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(&u32::MAX.to_be_bytes())?;
+    // The server hangs up rather than waiting for 4 GiB of payload
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    assert_eq!(stream.read(&mut [0; 1])?, 0);
+    drop(stream);
+
+    let mut client = KvsClient::new(Some(addr.to_owned()))?;
+    assert!(matches!(
+        client.send(Command::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+        })?,
+        Response::SetOk(_)
+    ));
+    drop(client);
+
+    // Nor can a frame over the limit be sent
+    let mut buffer = Vec::new();
+    let value = "v".repeat(MAX_FRAME_LEN);
+    assert!(write_frame(
+        &mut buffer,
+        &Command::Set {
+            key: "key1".to_owned(),
+            value,
+        }
+    )
+    .is_err());
+    assert!(buffer.is_empty());
+
+    stop_server(server)
+}
+
+// A response over the frame limit should come back as an error on a connection that
+// stays usable
+#[test]
+fn oversized_response_is_an_error() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4046";
+    let server = start_server(addr, temp_dir.path());
+
+    let mut client = KvsClient::new(Some(addr.to_owned()))?;
+    for key_id in 0..5 {
+        client.send(Command::Set {
+            key: format!("key{}", key_id),
+            value: "v".repeat(MAX_FRAME_LEN / 4),
+        })?;
+    }
+    match client.send(Command::PrefixScan {
+        prefix: "key".to_owned(),
+    })? {
+        Response::Err(ErrorKind::Internal(message)) => assert_eq!(message, "response too large"),
+        response => panic!("unexpected response {:?}", response),
+    }
+    client.ping()?;
+    drop(client);
+
+    stop_server(server)
+}
+
+// Framed messages should let one connection carry several commands
+#[test]
+fn framed_commands_on_one_connection() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4012";
+    let server = start_server(addr, temp_dir.path());
+
+    let mut stream = TcpStream::connect(addr)?;
+    write_frame(
+        &mut stream,
+        &Command::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+        },
+    )?;
+    write_frame(
+        &mut stream,
+        &Command::Get {
+            key: "key1".to_owned(),
+        },
+    )?;
+    assert!(matches!(
+        read_frame::<_, Response>(&mut stream)?,
+        Response::SetOk(_)
+    ));
+    match read_frame::<_, Response>(&mut stream)? {
+        Response::GetOk(value) => assert_eq!(value, "value1"),
+        response => panic!("unexpected response {:?}", response),
+    }
+    drop(stream);
+
+    stop_server(server)
+}
+
+// A single client should be able to issue several commands on its connection
+#[test]
+fn client_reuses_connection() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4013";
+    let server = start_server(addr, temp_dir.path());
+
+    let mut client = KvsClient::new(Some(addr.to_owned()))?;
+    assert!(matches!(
+        client.send(Command::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+        })?,
+        Response::SetOk(_)
+    ));
+    match client.send(Command::Get {
+        key: "key1".to_owned(),
+    })? {
+        Response::GetOk(value) => assert_eq!(value, "value1"),
+        response => panic!("unexpected response {:?}", response),
+    }
+    assert!(matches!(
+        client.send(Command::Rm {
+            key: "key1".to_owned(),
+        })?,
+        Response::RmOk
+    ));
+    assert!(matches!(
+        client.send(Command::Get {
+            key: "key1".to_owned(),
+        })?,
+        Response::Err(_)
+    ));
+    drop(client);
+
+    // Closing the connection ends that session only
+    let mut client = KvsClient::new(Some(addr.to_owned()))?;
+    assert!(matches!(
+        client.send(Command::Get {
+            key: "key1".to_owned(),
+        })?,
+        Response::Err(_)
+    ));
+    drop(client);
+
+    stop_server(server)
+}
+
+// A server that never answers should produce a timeout rather than a hang
+#[test]
+fn client_times_out() -> Result<()> {
+    // The kernel completes the handshake, but nothing ever reads or responds
+    let listener = TcpListener::bind("127.0.0.1:4014")?;
+    let timeout = Duration::from_millis(200);
+    let started = Instant::now();
+
+    let mut client = KvsClient::with_timeout(Some("127.0.0.1:4014".to_owned()), Some(timeout))?;
+    match client.send(Command::Get {
+        key: "key1".to_owned(),
+    }) {
+        Err(KvStoreError::Timeout) => {}
+        other => panic!("expected a timeout, got {:?}", other),
+    }
+    assert!(started.elapsed() < Duration::from_secs(5));
+    drop(listener);
+
+    Ok(())
+}
+
+// Clients should send each command right away, unless told to let Nagle's algorithm wait
+#[test]
+fn client_nodelay() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:4040")?;
+
+    let mut client = KvsClient::new(Some("127.0.0.1:4040".to_owned()))?;
+    assert!(client.nodelay()?);
+    client.set_nodelay(false)?;
+    assert!(!client.nodelay()?);
+    drop(listener);
+
+    Ok(())
+}
+
+/// Serves on `addr` with `server` and returns whether the stream it accepted for a client
+/// has Nagle's algorithm off. The stream is found among this process's descriptors by its
+/// addresses.
+#[cfg(unix)]
+fn accepted_nodelay(mut server: KvsServer, addr: &str) -> Result<bool> {
+    use std::mem::ManuallyDrop;
+    use std::os::unix::io::FromRawFd;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let server_stop = stop.clone();
+    let handle = thread::spawn(move || server.run_until(server_stop));
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = TcpStream::connect(addr)?;
+    // Answered only once the server has accepted the stream and set its options
+    write_frame(&mut client, &Command::Ping)?;
+    read_frame::<_, Response>(&mut client)?;
+    let (client_addr, server_addr) = (client.local_addr()?, client.peer_addr()?);
+    let mut nodelay = None;
+    for fd in 0..4096 {
+        // SAFETY: the stream is never dropped, so it only queries the descriptor and
+        // doesn't close it
+        let stream = ManuallyDrop::new(unsafe { TcpStream::from_raw_fd(fd) });
+        if stream.local_addr().ok() == Some(server_addr)
+            && stream.peer_addr().ok() == Some(client_addr)
+        {
+            nodelay = Some(stream.nodelay()?);
+            break;
+        }
+    }
+    drop(client);
+
+    stop_server((stop, handle))?;
+    Ok(nodelay.expect("the accepted stream wasn't found"))
+}
+
+// The server should turn Nagle's algorithm off for accepted streams, unless the config
+// file or the builder says otherwise
+#[cfg(unix)]
+#[test]
+fn server_nodelay() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4044";
+    let new_server = |config| {
+        KvsServer::new(
+            Some(addr.to_owned()),
+            Some("memory".to_owned()),
+            temp_dir.path(),
+            config,
+        )
+    };
+
+    assert!(accepted_nodelay(new_server(None)?, addr)?);
+    assert!(!accepted_nodelay(
+        new_server(None)?.tcp_nodelay(false),
+        addr
+    )?);
+
+    let config = temp_dir.path().join("kvs.toml");
+    fs::write(&config, "tcp_nodelay = false\n")?;
+    assert!(!accepted_nodelay(new_server(Some(config.clone()))?, addr)?);
+    assert!(accepted_nodelay(
+        new_server(Some(config))?.tcp_nodelay(true),
+        addr
+    )?);
+
+    Ok(())
+}
+
+/// A `kvs-server` process, killed when dropped so a failing test doesn't leave it running.
+struct ServerProcess(process::Child);
+
+impl Drop for ServerProcess {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+// A reconnecting client should carry on once a killed server has been restarted
+#[test]
+fn reconnecting_client() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4041";
+    let spawn_server = || {
+        ServerProcess(
+            process::Command::cargo_bin("kvs-server")
+                .unwrap()
+                .args(["--addr", addr])
+                .current_dir(&temp_dir)
+                .spawn()
+                .unwrap(),
+        )
+    };
+    let server = spawn_server();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = ReconnectingClient::new(Some(addr.to_owned()))?;
+    assert!(matches!(
+        client.send(Command::Select { db: 1 })?,
+        Response::SelectOk
+    ));
+    assert!(matches!(
+        client.send(Command::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+        })?,
+        Response::SetOk(_)
+    ));
+
+    drop(server);
+    // Not waited for: the client keeps reconnecting until the server is back up
+    let _server = spawn_server();
+
+    match client.send(Command::Get {
+        key: "key1".to_owned(),
+    })? {
+        Response::GetOk(value) => assert_eq!(value, "value1"),
+        response => panic!("unexpected response {:?}", response),
+    }
+    assert!(matches!(
+        client.send(Command::Set {
+            key: "key2".to_owned(),
+            value: "value2".to_owned(),
+        })?,
+        Response::SetOk(_)
+    ));
+
+    // Both keys went to the database selected before the restart
+    let mut db0 = KvsClient::new(Some(addr.to_owned()))?;
+    assert!(matches!(
+        db0.send(Command::Get {
+            key: "key2".to_owned(),
+        })?,
+        Response::Err(ErrorKind::KeyNotFound)
+    ));
+
+    Ok(())
+}
+
+// A command that timed out may have run, so only one that is safe to run twice should be
+// sent again
+#[test]
+fn reconnecting_client_timeout() -> Result<()> {
+    let addr = "127.0.0.1:4045";
+    // Accepts connections but never answers
+    let listener = TcpListener::bind(addr)?;
+    let accepted = |listener: &TcpListener| {
+        let mut streams = Vec::new();
+        while let Ok((stream, _)) = listener.accept() {
+            streams.push(stream);
+        }
+        streams.len()
+    };
+    listener.set_nonblocking(true)?;
+    let connect = || {
+        ReconnectingClient::with_timeout(Some(addr.to_owned()), Some(Duration::from_millis(200)))
+    };
+
+    assert!(matches!(
+        connect()?.send(Command::Incr {
+            key: "counter".to_owned(),
+            delta: 1,
+        }),
+        Err(KvStoreError::Timeout)
+    ));
+    assert_eq!(accepted(&listener), 1);
+
+    // A read is sent again on a new connection
+    assert!(matches!(
+        connect()?.send(Command::Get {
+            key: "key1".to_owned(),
+        }),
+        Err(KvStoreError::Timeout)
+    ));
+    assert_eq!(accepted(&listener), 2);
+
+    Ok(())
+}
+
+// Removing a missing key is reported to the client and the server keeps running
+#[test]
+fn remove_missing_key_keeps_serving() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4015";
+    let server = start_server(addr, temp_dir.path());
+
+    let mut client = KvsClient::new(Some(addr.to_owned()))?;
+    match client.send(Command::Rm {
+        key: "key1".to_owned(),
+    })? {
+        Response::Err(kind) => assert_eq!(kind, ErrorKind::KeyNotFound),
+        response => panic!("unexpected response {:?}", response),
+    }
+    assert!(matches!(
+        client.send(Command::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+        })?,
+        Response::SetOk(_)
+    ));
+    drop(client);
+
+    let mut client = KvsClient::new(Some(addr.to_owned()))?;
+    match client.send(Command::Get {
+        key: "key1".to_owned(),
+    })? {
+        Response::GetOk(value) => assert_eq!(value, "value1"),
+        response => panic!("unexpected response {:?}", response),
+    }
+    drop(client);
+
+    stop_server(server)
+}
+
+// Opening another directory should switch the server to a separate store
+#[test]
+fn open_switches_store() -> Result<()> {
+    let first_dir = TempDir::new().expect("unable to create temporary working directory");
+    let second_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4016";
+    let server = start_server(addr, first_dir.path());
+
+    let mut client = KvsClient::new(Some(addr.to_owned()))?;
+    client.send(Command::Set {
+        key: "key1".to_owned(),
+        value: "value1".to_owned(),
+    })?;
+
+    assert!(matches!(
+        client.send(Command::Open {
+            path: second_dir.path().to_owned(),
+        })?,
+        Response::OpenOk
+    ));
+    assert!(matches!(
+        client.send(Command::Get {
+            key: "key1".to_owned(),
+        })?,
+        Response::Err(_)
+    ));
+    client.send(Command::Set {
+        key: "key2".to_owned(),
+        value: "value2".to_owned(),
+    })?;
+
+    // A missing directory is refused and the current store stays open
+    assert!(matches!(
+        client.send(Command::Open {
+            path: second_dir.path().join("missing"),
+        })?,
+        Response::Err(_)
+    ));
+    match client.send(Command::Get {
+        key: "key2".to_owned(),
+    })? {
+        Response::GetOk(value) => assert_eq!(value, "value2"),
+        response => panic!("unexpected response {:?}", response),
+    }
+
+    assert!(matches!(
+        client.send(Command::Open {
+            path: first_dir.path().to_owned(),
+        })?,
+        Response::OpenOk
+    ));
+    match client.send(Command::Get {
+        key: "key1".to_owned(),
+    })? {
+        Response::GetOk(value) => assert_eq!(value, "value1"),
+        response => panic!("unexpected response {:?}", response),
+    }
+    assert!(matches!(
+        client.send(Command::Get {
+            key: "key2".to_owned(),
+        })?,
+        Response::Err(_)
+    ));
+    drop(client);
+
+    stop_server(server)
+}
+
+// The server should report the version it was built from
+#[test]
+fn server_version() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4017";
+    let server = start_server(addr, temp_dir.path());
+
+    let mut client = KvsClient::new(Some(addr.to_owned()))?;
+    match client.send(Command::Version)? {
+        Response::Version(version) => assert_eq!(version, env!("CARGO_PKG_VERSION")),
+        response => panic!("unexpected response {:?}", response),
+    }
+    drop(client);
+
+    stop_server(server)
+}
+
+// A flush should only answer once earlier writes are on disk for any reader to see
+#[test]
+fn server_flush() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4035";
+    let server = start_server(addr, temp_dir.path());
+
+    let mut client = KvsClient::new(Some(addr.to_owned()))?;
+    for iter in 0..5 {
+        client.send(Command::Set {
+            key: format!("key{}", iter),
+            value: format!("value{}", iter),
+        })?;
+    }
+    assert!(matches!(client.send(Command::Flush)?, Response::Flushed));
+
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    for iter in 0..5 {
+        assert_eq!(
+            store.get(format!("key{}", iter))?,
+            Some(format!("value{}", iter))
+        );
+    }
+    drop(client);
+
+    stop_server(server)
+}
+
+// A key set after selecting a database should be invisible to a connection on another
+#[test]
+fn server_select() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4036";
+    let server = start_server(addr, temp_dir.path());
+
+    let mut db0 = KvsClient::new(Some(addr.to_owned()))?;
+    let mut db1 = KvsClient::new(Some(addr.to_owned()))?;
+    assert!(matches!(
+        db1.send(Command::Select { db: 1 })?,
+        Response::SelectOk
+    ));
+    db0.send(Command::Set {
+        key: "key".to_owned(),
+        value: "value0".to_owned(),
+    })?;
+    db1.send(Command::Set {
+        key: "key".to_owned(),
+        value: "value1".to_owned(),
+    })?;
+    match db1.send(Command::Get {
+        key: "key".to_owned(),
+    })? {
+        Response::GetOk(value) => assert_eq!(value, "value1"),
+        response => panic!("unexpected response {:?}", response),
+    }
+    db1.send(Command::Rm {
+        key: "key".to_owned(),
+    })?;
+    match db0.send(Command::Get {
+        key: "key".to_owned(),
+    })? {
+        Response::GetOk(value) => assert_eq!(value, "value0"),
+        response => panic!("unexpected response {:?}", response),
+    }
+
+    // A key forged to look like database 1's is refused
+    assert!(matches!(
+        db0.send(Command::Set {
+            key: "\u{0}1\u{0}key".to_owned(),
+            value: "forged".to_owned(),
+        })?,
+        Response::Err(ErrorKind::Internal(_))
+    ));
+    assert!(matches!(
+        db1.send(Command::Get {
+            key: "key".to_owned(),
+        })?,
+        Response::Err(ErrorKind::KeyNotFound)
+    ));
+    drop(db0);
+    drop(db1);
+
+    stop_server(server)
+}
+
+// Errors should arrive typed, so the client can rebuild the `KvStoreError` behind them
+#[test]
+fn server_typed_errors() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4038";
+    let server = start_server(addr, temp_dir.path());
+
+    let mut client = KvsClient::new(Some(addr.to_owned()))?;
+    let response = client.send(Command::Rm {
+        key: "key1".to_owned(),
+    })?;
+    assert!(matches!(response, Response::Err(ErrorKind::KeyNotFound)));
+    assert!(matches!(
+        response.into_result(),
+        Err(KvStoreError::KeyNotFound)
+    ));
+    assert!(matches!(
+        client
+            .send(Command::Incr {
+                key: "key1".to_owned(),
+                delta: 1,
+            })?
+            .into_result(),
+        Ok(Response::IncrOk(1))
+    ));
+    assert!(matches!(
+        client.send(Command::Select { db: 1 })?.into_result(),
+        Ok(Response::SelectOk)
+    ));
+    drop(client);
+
+    stop_server(server)
+}
+
+// Stats should be available over the network
+#[test]
+fn server_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4018";
+    let server = start_server(addr, temp_dir.path());
+
+    let mut client = KvsClient::new(Some(addr.to_owned()))?;
+    for iter in 0..5 {
+        client.send(Command::Set {
+            key: format!("key{}", iter),
+            value: format!("value{}", iter),
+        })?;
+    }
+    client.send(Command::Set {
+        key: "key0".to_owned(),
+        value: "value5".to_owned(),
+    })?;
+    match client.send(Command::Stats)? {
+        Response::Stats {
+            live_keys,
+            dirt_bytes,
+            log_size_bytes,
+        } => {
+            assert_eq!(live_keys, 5);
+            assert!(dirt_bytes > 0);
+            assert!(log_size_bytes > dirt_bytes);
+        }
+        response => panic!("unexpected response {:?}", response),
+    }
+    drop(client);
+
+    stop_server(server)
+}
+
+// Compare-and-swap should report over the network whether it swapped
+#[test]
+fn server_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4019";
+    let server = start_server(addr, temp_dir.path());
+
+    let mut client = KvsClient::new(Some(addr.to_owned()))?;
+    let mut cas = |expected: Option<&str>, new: &str| {
+        client.send(Command::Cas {
+            key: "key1".to_owned(),
+            expected: expected.map(str::to_owned),
+            new: new.to_owned(),
+        })
+    };
+    assert!(matches!(cas(None, "value1")?, Response::CasOk(true)));
+    assert!(matches!(cas(None, "value2")?, Response::CasOk(false)));
+    assert!(matches!(
+        cas(Some("value2"), "value3")?,
+        Response::CasOk(false)
+    ));
+    assert!(matches!(
+        cas(Some("value1"), "value3")?,
+        Response::CasOk(true)
+    ));
+    match client.send(Command::Get {
+        key: "key1".to_owned(),
+    })? {
+        Response::GetOk(value) => assert_eq!(value, "value3"),
+        response => panic!("unexpected response {:?}", response),
+    }
+    drop(client);
+
+    stop_server(server)
+}
+
+// Increments should come back with the new value, or an error for non-integers
+#[test]
+fn server_incr() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4020";
+    let server = start_server(addr, temp_dir.path());
+
+    let mut client = KvsClient::new(Some(addr.to_owned()))?;
+    let mut incr = |key: &str, delta: i64| {
+        client.send(Command::Incr {
+            key: key.to_owned(),
+            delta,
+        })
+    };
+    assert!(matches!(incr("counter", 4)?, Response::IncrOk(4)));
+    assert!(matches!(incr("counter", -6)?, Response::IncrOk(-2)));
+    client.send(Command::Set {
+        key: "key1".to_owned(),
+        value: "value1".to_owned(),
+    })?;
+    match client.send(Command::Incr {
+        key: "key1".to_owned(),
+        delta: 1,
+    })? {
+        Response::Err(ErrorKind::Internal(message)) => assert!(message.contains("not an integer")),
+        response => panic!("unexpected response {:?}", response),
+    }
+    drop(client);
+
+    stop_server(server)
+}
+
+// A scan should return every pair under the prefix in one response
+#[test]
+fn server_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4021";
+    let server = start_server(addr, temp_dir.path());
+
+    let mut client = KvsClient::new(Some(addr.to_owned()))?;
+    for key in ["user:1", "user:2", "item:1"] {
+        client.send(Command::Set {
+            key: key.to_owned(),
+            value: format!("{} value", key),
+        })?;
+    }
+    match client.send(Command::PrefixScan {
+        prefix: "user:".to_owned(),
+    })? {
+        Response::Values(pairs) => assert_eq!(
+            pairs,
+            vec![
+                ("user:1".to_owned(), "user:1 value".to_owned()),
+                ("user:2".to_owned(), "user:2 value".to_owned()),
+            ]
+        ),
+        response => panic!("unexpected response {:?}", response),
+    }
+    drop(client);
+
+    stop_server(server)
+}
+
+// A range scan should return the pairs between its bounds, with open ends allowed
+#[test]
+fn server_range_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4022";
+    let server = start_server(addr, temp_dir.path());
+
+    let mut client = KvsClient::new(Some(addr.to_owned()))?;
+    for key in ["a", "b", "c", "d", "e"] {
+        client.send(Command::Set {
+            key: key.to_owned(),
+            value: key.to_uppercase(),
+        })?;
+    }
+    let mut scan = |start: Option<&str>, end: Option<&str>| -> Result<Vec<String>> {
+        match client.send(Command::Scan {
+            start: start.map(str::to_owned),
+            end: end.map(str::to_owned),
+        })? {
+            Response::Values(pairs) => Ok(pairs.into_iter().map(|(key, _)| key).collect()),
+            response => panic!("unexpected response {:?}", response),
+        }
+    };
+    assert_eq!(scan(Some("b"), Some("e"))?, vec!["b", "c", "d"]);
+    assert_eq!(scan(None, None)?, vec!["a", "b", "c", "d", "e"]);
+    assert_eq!(scan(Some("d"), None)?, vec!["d", "e"]);
+    assert!(scan(Some("e"), Some("b"))?.is_empty());
+    drop(client);
+
+    stop_server(server)
+}
+
+/// A logger that keeps every message, so tests can look for the lines they expect.
+struct CapturingLogger {
+    lines: Mutex<Vec<String>>,
+}
+
+impl log::Log for CapturingLogger {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        self.lines
+            .lock()
+            .unwrap()
+            .push(format!("{} {}", record.level(), record.args()));
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: CapturingLogger = CapturingLogger {
+    lines: Mutex::new(Vec::new()),
+};
+
+// Each request should be logged with its command, key, status and timing
+#[test]
+fn server_logs_requests() -> Result<()> {
+    log::set_logger(&LOGGER).expect("another logger is already installed");
+    log::set_max_level(log::LevelFilter::Info);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4023";
+    let server = start_server(addr, temp_dir.path());
+
+    let mut client = KvsClient::new(Some(addr.to_owned()))?;
+    client.send(Command::Set {
+        key: "logged-key".to_owned(),
+        value: "value1".to_owned(),
+    })?;
+    drop(client);
+    stop_server(server)?;
+
+    let lines = LOGGER.lines.lock().unwrap();
+    assert!(lines.iter().any(|line| line.starts_with("INFO")
+        && line.contains("command=set key=logged-key status=ok elapsed=")));
+
+    Ok(())
+}
+
+// A batch should run every command in order and answer them in one response
+#[test]
+fn server_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4024";
+    let server = start_server(addr, temp_dir.path());
+
+    let mut client = KvsClient::new(Some(addr.to_owned()))?;
+    let mut commands: Vec<Command> = (0..100)
+        .map(|key_id| Command::Set {
+            key: format!("key{}", key_id),
+            value: format!("value{}", key_id),
+        })
+        .collect();
+    commands.push(Command::Get {
+        key: "key42".to_owned(),
+    });
+
+    let responses = client.send_batch(commands)?;
+    assert_eq!(responses.len(), 101);
+    assert!(responses[..100]
+        .iter()
+        .all(|response| matches!(response, Response::SetOk(_))));
+    match &responses[100] {
+        Response::GetOk(value) => assert_eq!(value, "value42"),
+        response => panic!("unexpected response {:?}", response),
+    }
+
+    // Nested batches are refused as a whole
+    let nested = vec![
+        Command::Set {
+            key: "key100".to_owned(),
+            value: "value100".to_owned(),
+        },
+        Command::Batch {
+            commands: Vec::new(),
+        },
+    ];
+    assert!(matches!(
+        client.send_batch(nested),
+        Err(KvStoreError::ServerError(_))
+    ));
+    assert!(matches!(
+        client.send(Command::Get {
+            key: "key100".to_owned(),
+        })?,
+        Response::Err(_)
+    ));
+    drop(client);
+
+    stop_server(server)
+}
+
+// A ping should be answered with a pong and a measurable round trip
+#[test]
+fn server_ping() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4026";
+    let server = start_server(addr, temp_dir.path());
+
+    let mut client = KvsClient::new(Some(addr.to_owned()))?;
+    assert!(matches!(client.send(Command::Ping)?, Response::Pong));
+    assert!(client.ping()? > Duration::ZERO);
+    drop(client);
+
+    stop_server(server)
+}
+
+// The memory engine should serve requests without writing anything to disk
+#[test]
+fn server_memory_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4028";
+    let stop = Arc::new(AtomicBool::new(false));
+    let server_stop = stop.clone();
+    let mut server = KvsServer::new(
+        Some(addr.to_owned()),
+        Some("memory".to_owned()),
+        temp_dir.path(),
+        None,
+    )?;
+    let handle = thread::spawn(move || server.run_until(server_stop));
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::new(Some(addr.to_owned()))?;
+    assert!(matches!(
+        client.send(Command::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+        })?,
+        Response::SetOk(None)
+    ));
+    match client.send(Command::Get {
+        key: "key1".to_owned(),
+    })? {
+        Response::GetOk(value) => assert_eq!(value, "value1"),
+        response => panic!("unexpected response {:?}", response),
+    }
+    assert!(matches!(
+        client.send(Command::Rm {
+            key: "key1".to_owned(),
+        })?,
+        Response::RmOk
+    ));
+    assert!(matches!(
+        client.send(Command::Get {
+            key: "key1".to_owned(),
+        })?,
+        Response::Err(_)
+    ));
+    drop(client);
+    stop_server((stop, handle))?;
+
+    assert_eq!(fs::read_dir(temp_dir.path())?.count(), 0);
+
+    Ok(())
+}
+
+// Each set should report where it landed, right after the one before it
+#[test]
+fn server_set_position() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = start_server("127.0.0.1:4029", temp_dir.path());
+
+    let mut client = KvsClient::new(Some("127.0.0.1:4029".to_owned()))?;
+    let mut next_start = 0;
+    for iter in 0..5 {
+        match client.send(Command::Set {
+            key: format!("key{}", iter),
+            value: format!("value{}", iter),
+        })? {
+            Response::SetOk(Some(position)) => {
+                assert_eq!(position.gen, 1);
+                assert_eq!(position.start, next_start);
+                assert!(position.length > 0);
+                next_start = position.start + position.length;
+            }
+            response => panic!("unexpected response {:?}", response),
+        }
+    }
+    drop(client);
+
+    stop_server(server)?;
+    assert_eq!(
+        fs::metadata(temp_dir.path().join("1.log"))?.len(),
+        next_start
+    );
+
+    Ok(())
+}
+
+// A set and get should round-trip over a Unix domain socket, which is removed on shutdown
+#[cfg(unix)]
+#[test]
+fn server_unix_socket() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let socket = temp_dir.path().join("kvs.sock");
+    let addr = format!("unix:{}", socket.display());
+    let stop = Arc::new(AtomicBool::new(false));
+    let server_stop = stop.clone();
+    let mut server = KvsServer::new(Some(addr.clone()), None, temp_dir.path(), None)?;
+    let handle = thread::spawn(move || server.run_until(server_stop));
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::new(Some(addr))?;
+    assert!(matches!(
+        client.send(Command::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+        })?,
+        Response::SetOk(_)
+    ));
+    match client.send(Command::Get {
+        key: "key1".to_owned(),
+    })? {
+        Response::GetOk(value) => assert_eq!(value, "value1"),
+        response => panic!("unexpected response {:?}", response),
+    }
+    drop(client);
+
+    stop_server((stop, handle))?;
+    assert!(!socket.exists());
+
+    Ok(())
+}
+
+// Binding a Unix socket should replace a stale socket but never another kind of file
+#[cfg(unix)]
+#[test]
+fn server_unix_socket_path_in_use() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("data.log");
+    fs::write(&path, "keep me")?;
+    let mut server = KvsServer::new(
+        Some(format!("unix:{}", path.display())),
+        Some("memory".to_owned()),
+        temp_dir.path(),
+        None,
+    )?;
+    match server.run_until(Arc::new(AtomicBool::new(true))) {
+        Err(KvStoreError::IoError(err)) => assert_eq!(err.kind(), std::io::ErrorKind::AddrInUse),
+        result => panic!("unexpected result {:?}", result),
+    }
+    assert_eq!(fs::read_to_string(&path)?, "keep me");
+
+    // A socket left behind by a server that is gone is replaced
+    let socket = temp_dir.path().join("kvs.sock");
+    drop(std::os::unix::net::UnixListener::bind(&socket)?);
+    let mut server = KvsServer::new(
+        Some(format!("unix:{}", socket.display())),
+        Some("memory".to_owned()),
+        temp_dir.path(),
+        None,
+    )?;
+    server.run_until(Arc::new(AtomicBool::new(true)))?;
+
+    Ok(())
+}
+
+// Only a connection that opens with the right token should be served
+#[test]
+fn server_auth_token() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4030";
+    let stop = Arc::new(AtomicBool::new(false));
+    let server_stop = stop.clone();
+    let mut server = KvsServer::new(Some(addr.to_owned()), None, temp_dir.path(), None)?
+        .with_auth_token("secret");
+    let handle = thread::spawn(move || server.run_until(server_stop));
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::new(Some(addr.to_owned()))?;
+    client.authenticate("secret".to_owned())?;
+    assert!(matches!(
+        client.send(Command::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+        })?,
+        Response::SetOk(_)
+    ));
+    drop(client);
+
+    // Without authenticating, the first command is refused and the connection closed
+    let mut client = KvsClient::new(Some(addr.to_owned()))?;
+    match client.send(Command::Get {
+        key: "key1".to_owned(),
+    })? {
+        Response::Err(kind) => assert_eq!(kind, ErrorKind::Unauthorized),
+        response => panic!("unexpected response {:?}", response),
+    }
+    assert!(client.send(Command::Ping).is_err());
+    drop(client);
+
+    let mut client = KvsClient::new(Some(addr.to_owned()))?;
+    match client.authenticate("secreT".to_owned()) {
+        Err(KvStoreError::Unauthorized) => {}
+        result => panic!("unexpected result {:?}", result),
+    }
+    drop(client);
+
+    stop_server((stop, handle))
+}
+
+// A read-only server should serve reads of existing data and reject every write, next to
+// a store still open for writing
+#[test]
+fn server_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let addr = "127.0.0.1:4037";
+    let stop = Arc::new(AtomicBool::new(false));
+    let server_stop = stop.clone();
+    let mut server = KvsServer::new_read_only(Some(addr.to_owned()), None, temp_dir.path(), None)?;
+    let handle = thread::spawn(move || server.run_until(server_stop));
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::new(Some(addr.to_owned()))?;
+    match client.send(Command::Set {
+        key: "key1".to_owned(),
+        value: "value2".to_owned(),
+    })? {
+        Response::Err(kind) => assert_eq!(kind, ErrorKind::ReadOnly),
+        response => panic!("unexpected response {:?}", response),
+    }
+    match client.send(Command::Rm {
+        key: "key1".to_owned(),
+    })? {
+        Response::Err(kind) => assert_eq!(kind, ErrorKind::ReadOnly),
+        response => panic!("unexpected response {:?}", response),
+    }
+    match client.send(Command::Get {
+        key: "key1".to_owned(),
+    })? {
+        Response::GetOk(value) => assert_eq!(value, "value1"),
+        response => panic!("unexpected response {:?}", response),
+    }
+    assert!(matches!(
+        client.send(Command::Stats)?,
+        Response::Stats { live_keys: 1, .. }
+    ));
+    drop(client);
+    drop(store);
+
+    stop_server((stop, handle))
+}
+
+// A watching connection should hear about changes made on another connection
+#[test]
+fn server_watch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = start_server("127.0.0.1:4031", temp_dir.path());
+
+    let mut watcher = KvsClient::with_timeout(
+        Some("127.0.0.1:4031".to_owned()),
+        Some(Duration::from_secs(5)),
+    )?;
+    watcher.watch("key1".to_owned())?;
+
+    let mut client = KvsClient::new(Some("127.0.0.1:4031".to_owned()))?;
+    for cmd in [
+        Command::Set {
+            key: "key2".to_owned(),
+            value: "value2".to_owned(),
+        },
+        Command::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+        },
+        Command::Rm {
+            key: "key1".to_owned(),
+        },
+    ] {
+        client.send(cmd)?;
+    }
+    assert_eq!(
+        watcher.next_change()?,
+        ("key1".to_owned(), Some("value1".to_owned()))
+    );
+    assert_eq!(watcher.next_change()?, ("key1".to_owned(), None));
+
+    // After unwatching, the connection serves commands again
+    watcher.unwatch()?;
+    client.send(Command::Set {
+        key: "key1".to_owned(),
+        value: "value3".to_owned(),
+    })?;
+    match watcher.send(Command::Get {
+        key: "key1".to_owned(),
+    })? {
+        Response::GetOk(value) => assert_eq!(value, "value3"),
+        response => panic!("unexpected response {:?}", response),
+    }
+    drop(watcher);
+    drop(client);
+
+    stop_server(server)
+}
+
+// Watching a key twice should forward each of its changes once
+#[test]
+fn server_watch_twice() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = start_server("127.0.0.1:4047", temp_dir.path());
+
+    let mut watcher = KvsClient::with_timeout(
+        Some("127.0.0.1:4047".to_owned()),
+        Some(Duration::from_secs(5)),
+    )?;
+    watcher.watch("key1".to_owned())?;
+    watcher.watch("key1".to_owned())?;
+    watcher.watch("key2".to_owned())?;
+
+    let mut client = KvsClient::new(Some("127.0.0.1:4047".to_owned()))?;
+    for (key, value) in [("key1", "value1"), ("key2", "value2")] {
+        client.send(Command::Set {
+            key: key.to_owned(),
+            value: value.to_owned(),
+        })?;
+    }
+    assert_eq!(
+        watcher.next_change()?,
+        ("key1".to_owned(), Some("value1".to_owned()))
+    );
+    assert_eq!(
+        watcher.next_change()?,
+        ("key2".to_owned(), Some("value2".to_owned()))
+    );
+    drop(watcher);
+    drop(client);
+
+    stop_server(server)
+}
+
+// Idle connections should be closed, so they can't hold every worker of the pool
+#[test]
+fn idle_connections_time_out() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4043";
+    let stop = Arc::new(AtomicBool::new(false));
+    let server_stop = stop.clone();
+    let mut server = KvsServer::new(Some(addr.to_owned()), None, temp_dir.path(), None)?
+        .idle_timeout(Some(Duration::from_millis(500)));
+    let handle = thread::spawn(move || server.run_until(server_stop));
+    thread::sleep(Duration::from_secs(1));
+
+    // More idle connections than the pool can have workers
+    let workers = thread::available_parallelism().map_or(4, |threads| threads.get().max(4));
+    let mut idle = (0..workers + 1)
+        .map(|_| TcpStream::connect(addr))
+        .collect::<std::io::Result<Vec<_>>>()?;
+
+    let mut client = KvsClient::with_timeout(Some(addr.to_owned()), Some(Duration::from_secs(5)))?;
+    client.ping()?;
+
+    for stream in &mut idle {
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        assert_eq!(stream.read(&mut [0; 1])?, 0);
+    }
+    drop(client);
+
+    stop_server((stop, handle))
+}